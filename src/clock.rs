@@ -0,0 +1,68 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Source of time for a node: timestamps for transactions and blocks and
+/// the delays between background loop iterations.
+pub trait Clock: Send + Sync + 'static {
+    /// Milliseconds since the unix epoch.
+    fn now_millis(&self) -> u64;
+
+    fn sleep(&self, duration: Duration) -> Sleep;
+
+    /// Seconds since the unix epoch.
+    fn now(&self) -> u64 {
+        self.now_millis() / 1000
+    }
+}
+
+/// Wall clock time, used by real nodes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Clock that starts at a fixed `epoch` and only moves with the tokio
+/// runtime's clock.
+///
+/// Combined with a paused runtime (`tokio::time::pause` or
+/// `#[tokio::test(start_paused = true)]`) time only advances when every
+/// task is waiting, so a run produces the same timestamps every time.
+#[derive(Debug, Clone, Copy)]
+pub struct VirtualClock {
+    epoch_millis: u64,
+    started: tokio::time::Instant,
+}
+
+impl VirtualClock {
+    /// Must be called from within a tokio runtime.
+    pub fn new(epoch_millis: u64) -> Self {
+        VirtualClock {
+            epoch_millis,
+            started: tokio::time::Instant::now(),
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now_millis(&self) -> u64 {
+        self.epoch_millis + self.started.elapsed().as_millis() as u64
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
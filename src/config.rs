@@ -0,0 +1,586 @@
+use crate::http::middleware::Rate;
+use crate::http::server::{RequestLimits, ServerConfig};
+use crate::ledger::MINING_COMPLEXITY;
+use crate::logging::{self, LogFormat};
+use crate::peers::NeighbourConfig;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::time::Duration;
+
+/// Everything a node needs to know before it starts.
+///
+/// Values are resolved in this order, later ones winning: built-in defaults,
+/// the JSON file given with `--config` (or `NODE_CONFIG`), `NODE_*`
+/// environment variables and finally command line flags.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    pub ip: String,
+    pub port: u16,
+    pub peers_file: String,
+
+    pub discovery_interval_secs: u64,
+    pub block_sync_interval_secs: u64,
+    pub tx_sync_interval_secs: u64,
+    pub block_creation_interval_secs: u64,
+    pub advertisement_interval_secs: u64,
+    pub ping_interval_secs: u64,
+    pub neighbour_rotation_interval_secs: u64,
+
+    pub difficulty: usize,
+    pub max_block_txs: usize,
+    pub http_threads: usize,
+    /// Requests that may wait for a free HTTP thread before new ones are
+    /// turned away.
+    pub http_queue_size: usize,
+    pub http_idle_timeout_secs: u64,
+    pub http_read_timeout_secs: u64,
+    pub http_write_timeout_secs: u64,
+    pub http_max_requests_per_connection: usize,
+    pub http_max_connections: usize,
+    pub http_max_header_bytes: usize,
+    pub http_max_headers: usize,
+    pub http_max_body_bytes: usize,
+    /// How long a shutdown waits for open requests and background jobs.
+    pub shutdown_timeout_secs: u64,
+
+    /// Per client IP token buckets for `POST /users` and `POST /transfers`.
+    /// A burst of 0 turns the limit off.
+    pub rate_limit_submit_burst: u32,
+    pub rate_limit_submit_per_sec: f64,
+    /// Same for the gossip routes `POST /transactions` and `POST /blocks`.
+    pub rate_limit_gossip_burst: u32,
+    pub rate_limit_gossip_per_sec: f64,
+    /// Same for `POST /peers`.
+    pub rate_limit_peers_burst: u32,
+    pub rate_limit_peers_per_sec: f64,
+
+    pub outbound_slots: usize,
+    pub inbound_slots: usize,
+    pub fanout: usize,
+
+    /// Seed for the node's random peer choices. Random when unset.
+    pub seed: Option<u64>,
+
+    /// Default level and per-module overrides, e.g. `info,p2p::peers=debug`.
+    pub log_filter: String,
+    pub log_format: LogFormat,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        let neighbours = NeighbourConfig::default();
+        let server = ServerConfig::default();
+
+        NodeConfig {
+            ip: "127.0.0.1".into(),
+            port: 5000,
+            peers_file: "peers_config.json".into(),
+
+            discovery_interval_secs: 30,
+            block_sync_interval_secs: 30,
+            tx_sync_interval_secs: 15,
+            block_creation_interval_secs: 60,
+            advertisement_interval_secs: 15,
+            ping_interval_secs: 10,
+            neighbour_rotation_interval_secs: 120,
+
+            difficulty: MINING_COMPLEXITY,
+            max_block_txs: 1000,
+            http_threads: 32,
+            http_queue_size: 256,
+            http_idle_timeout_secs: server.idle_timeout.as_secs(),
+            http_read_timeout_secs: server.read_timeout.as_secs(),
+            http_write_timeout_secs: server.write_timeout.as_secs(),
+            http_max_requests_per_connection: server.max_requests_per_connection,
+            http_max_connections: server.max_connections,
+            http_max_header_bytes: server.limits.max_header_bytes,
+            http_max_headers: server.limits.max_headers,
+            http_max_body_bytes: server.limits.max_body_bytes,
+            shutdown_timeout_secs: server.drain_timeout.as_secs(),
+
+            rate_limit_submit_burst: 20,
+            rate_limit_submit_per_sec: 5.0,
+            rate_limit_gossip_burst: 500,
+            rate_limit_gossip_per_sec: 100.0,
+            rate_limit_peers_burst: 20,
+            rate_limit_peers_per_sec: 2.0,
+
+            outbound_slots: neighbours.outbound_slots,
+            inbound_slots: neighbours.inbound_slots,
+            fanout: neighbours.fanout,
+
+            seed: None,
+
+            log_filter: "info".into(),
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: String,
+        error: std::io::Error,
+    },
+    Parse {
+        path: String,
+        error: serde_json::Error,
+    },
+    MissingValue(String),
+    InvalidValue {
+        key: String,
+        value: String,
+    },
+    UnknownFlag(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => write!(f, "failed to read {}: {}", path, error),
+            ConfigError::Parse { path, error } => write!(f, "could not parse {}: {}", path, error),
+            ConfigError::MissingValue(key) => write!(f, "missing value for {}", key),
+            ConfigError::InvalidValue { key, value } => {
+                write!(f, "invalid value {:?} for {}", value, key)
+            }
+            ConfigError::UnknownFlag(flag) => write!(f, "unknown flag {}", flag),
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl NodeConfig {
+    /// Builds the configuration from the process arguments and environment.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let env = |key: &str| std::env::var(key).ok();
+        Self::load(&args, env)
+    }
+
+    pub fn load(
+        args: &[String],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let config_path = flag_value(args, "--config")?.or_else(|| env("NODE_CONFIG"));
+
+        let mut config = match config_path {
+            Some(path) => Self::from_file(&path)?,
+            None => NodeConfig::default(),
+        };
+
+        for (var, key) in ENV_OVERRIDES {
+            if let Some(value) = env(var) {
+                config.set(key, &value)?;
+            }
+        }
+
+        config.apply_args(args)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(path).map_err(|error| ConfigError::Io {
+            path: path.into(),
+            error,
+        })?;
+
+        serde_json::from_str(&content).map_err(|error| ConfigError::Parse {
+            path: path.into(),
+            error,
+        })
+    }
+
+    /// Accepts `--key value` flags named after the config fields (with `-`
+    /// instead of `_`) and, for compatibility, a bare port number.
+    fn apply_args(&mut self, args: &[String]) -> Result<(), ConfigError> {
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            if arg == "--config" {
+                args.next();
+                continue;
+            }
+
+            match arg.strip_prefix("--") {
+                Some(flag) => {
+                    let key = flag.replace('-', "_");
+                    let value = args
+                        .next()
+                        .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                    self.set(&key, value).map_err(|e| match e {
+                        ConfigError::UnknownFlag(_) => ConfigError::UnknownFlag(arg.clone()),
+                        e => e,
+                    })?;
+                }
+                None => self.set("port", arg)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+            value.parse().map_err(|_| ConfigError::InvalidValue {
+                key: key.into(),
+                value: value.into(),
+            })
+        }
+
+        match key {
+            "ip" => self.ip = value.into(),
+            "port" => self.port = parse(key, value)?,
+            "peers_file" => self.peers_file = value.into(),
+            "discovery_interval_secs" => self.discovery_interval_secs = parse(key, value)?,
+            "block_sync_interval_secs" => self.block_sync_interval_secs = parse(key, value)?,
+            "tx_sync_interval_secs" => self.tx_sync_interval_secs = parse(key, value)?,
+            "block_creation_interval_secs" => {
+                self.block_creation_interval_secs = parse(key, value)?
+            }
+            "advertisement_interval_secs" => self.advertisement_interval_secs = parse(key, value)?,
+            "ping_interval_secs" => self.ping_interval_secs = parse(key, value)?,
+            "neighbour_rotation_interval_secs" => {
+                self.neighbour_rotation_interval_secs = parse(key, value)?
+            }
+            "difficulty" => self.difficulty = parse(key, value)?,
+            "max_block_txs" => self.max_block_txs = parse(key, value)?,
+            "http_threads" => self.http_threads = parse(key, value)?,
+            "http_queue_size" => self.http_queue_size = parse(key, value)?,
+            "http_idle_timeout_secs" => self.http_idle_timeout_secs = parse(key, value)?,
+            "http_read_timeout_secs" => self.http_read_timeout_secs = parse(key, value)?,
+            "http_write_timeout_secs" => self.http_write_timeout_secs = parse(key, value)?,
+            "http_max_requests_per_connection" => {
+                self.http_max_requests_per_connection = parse(key, value)?
+            }
+            "http_max_connections" => self.http_max_connections = parse(key, value)?,
+            "http_max_header_bytes" => self.http_max_header_bytes = parse(key, value)?,
+            "http_max_headers" => self.http_max_headers = parse(key, value)?,
+            "http_max_body_bytes" => self.http_max_body_bytes = parse(key, value)?,
+            "shutdown_timeout_secs" => self.shutdown_timeout_secs = parse(key, value)?,
+            "rate_limit_submit_burst" => self.rate_limit_submit_burst = parse(key, value)?,
+            "rate_limit_submit_per_sec" => self.rate_limit_submit_per_sec = parse(key, value)?,
+            "rate_limit_gossip_burst" => self.rate_limit_gossip_burst = parse(key, value)?,
+            "rate_limit_gossip_per_sec" => self.rate_limit_gossip_per_sec = parse(key, value)?,
+            "rate_limit_peers_burst" => self.rate_limit_peers_burst = parse(key, value)?,
+            "rate_limit_peers_per_sec" => self.rate_limit_peers_per_sec = parse(key, value)?,
+            "outbound_slots" => self.outbound_slots = parse(key, value)?,
+            "inbound_slots" => self.inbound_slots = parse(key, value)?,
+            "fanout" => self.fanout = parse(key, value)?,
+            "seed" => self.seed = Some(parse(key, value)?),
+            "log_filter" => self.log_filter = value.into(),
+            "log_format" => self.log_format = parse(key, value)?,
+            other => return Err(ConfigError::UnknownFlag(other.into())),
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let intervals = [
+            ("discovery_interval_secs", self.discovery_interval_secs),
+            ("block_sync_interval_secs", self.block_sync_interval_secs),
+            ("tx_sync_interval_secs", self.tx_sync_interval_secs),
+            (
+                "block_creation_interval_secs",
+                self.block_creation_interval_secs,
+            ),
+            (
+                "advertisement_interval_secs",
+                self.advertisement_interval_secs,
+            ),
+            ("ping_interval_secs", self.ping_interval_secs),
+            (
+                "neighbour_rotation_interval_secs",
+                self.neighbour_rotation_interval_secs,
+            ),
+            ("http_idle_timeout_secs", self.http_idle_timeout_secs),
+            ("http_read_timeout_secs", self.http_read_timeout_secs),
+            ("http_write_timeout_secs", self.http_write_timeout_secs),
+        ];
+
+        for (key, secs) in intervals {
+            if secs == 0 {
+                return Err(ConfigError::Invalid(format!("{} must be positive", key)));
+            }
+        }
+
+        if self.ip.parse::<std::net::IpAddr>().is_err() {
+            return Err(ConfigError::InvalidValue {
+                key: "ip".into(),
+                value: self.ip.clone(),
+            });
+        }
+
+        if logging::parse_filter(&self.log_filter).is_err() {
+            return Err(ConfigError::InvalidValue {
+                key: "log_filter".into(),
+                value: self.log_filter.clone(),
+            });
+        }
+
+        if !(1..=64).contains(&self.difficulty) {
+            return Err(ConfigError::Invalid(
+                "difficulty must be between 1 and 64".into(),
+            ));
+        }
+
+        let counts = [
+            ("max_block_txs", self.max_block_txs),
+            ("http_threads", self.http_threads),
+            ("http_queue_size", self.http_queue_size),
+            (
+                "http_max_requests_per_connection",
+                self.http_max_requests_per_connection,
+            ),
+            ("http_max_connections", self.http_max_connections),
+            ("http_max_header_bytes", self.http_max_header_bytes),
+            ("http_max_headers", self.http_max_headers),
+            ("http_max_body_bytes", self.http_max_body_bytes),
+            ("outbound_slots", self.outbound_slots),
+            ("inbound_slots", self.inbound_slots),
+            ("fanout", self.fanout),
+        ];
+
+        for (key, n) in counts {
+            if n == 0 {
+                return Err(ConfigError::Invalid(format!("{} must be positive", key)));
+            }
+        }
+
+        let rates = [
+            ("rate_limit_submit_per_sec", self.rate_limit_submit_per_sec),
+            ("rate_limit_gossip_per_sec", self.rate_limit_gossip_per_sec),
+            ("rate_limit_peers_per_sec", self.rate_limit_peers_per_sec),
+        ];
+
+        for (key, rate) in rates {
+            if !(rate.is_finite() && rate > 0.0) {
+                return Err(ConfigError::Invalid(format!("{} must be positive", key)));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+
+    pub fn server(&self) -> ServerConfig {
+        ServerConfig {
+            idle_timeout: Duration::from_secs(self.http_idle_timeout_secs),
+            read_timeout: Duration::from_secs(self.http_read_timeout_secs),
+            write_timeout: Duration::from_secs(self.http_write_timeout_secs),
+            max_requests_per_connection: self.http_max_requests_per_connection,
+            max_connections: self.http_max_connections,
+            drain_timeout: self.shutdown_timeout(),
+            limits: RequestLimits {
+                max_header_bytes: self.http_max_header_bytes,
+                max_headers: self.http_max_headers,
+                max_body_bytes: self.http_max_body_bytes,
+            },
+        }
+    }
+
+    /// Rate limits by route class, leaving out the disabled ones.
+    pub fn rate_limits(&self) -> HashMap<&'static str, Rate> {
+        let limits = [
+            (
+                "submit",
+                self.rate_limit_submit_burst,
+                self.rate_limit_submit_per_sec,
+            ),
+            (
+                "gossip",
+                self.rate_limit_gossip_burst,
+                self.rate_limit_gossip_per_sec,
+            ),
+            (
+                "peers",
+                self.rate_limit_peers_burst,
+                self.rate_limit_peers_per_sec,
+            ),
+        ];
+
+        limits
+            .into_iter()
+            .filter(|&(_, burst, _)| burst > 0)
+            .map(|(class, burst, per_sec)| (class, Rate { burst, per_sec }))
+            .collect()
+    }
+
+    pub fn neighbours(&self) -> NeighbourConfig {
+        NeighbourConfig {
+            outbound_slots: self.outbound_slots,
+            inbound_slots: self.inbound_slots,
+            fanout: self.fanout,
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn discovery_interval(&self) -> Duration {
+        Duration::from_secs(self.discovery_interval_secs)
+    }
+
+    pub fn block_sync_interval(&self) -> Duration {
+        Duration::from_secs(self.block_sync_interval_secs)
+    }
+
+    pub fn tx_sync_interval(&self) -> Duration {
+        Duration::from_secs(self.tx_sync_interval_secs)
+    }
+
+    pub fn block_creation_interval(&self) -> Duration {
+        Duration::from_secs(self.block_creation_interval_secs)
+    }
+
+    pub fn advertisement_interval(&self) -> Duration {
+        Duration::from_secs(self.advertisement_interval_secs)
+    }
+
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_secs)
+    }
+
+    pub fn neighbour_rotation_interval(&self) -> Duration {
+        Duration::from_secs(self.neighbour_rotation_interval_secs)
+    }
+}
+
+const ENV_OVERRIDES: [(&str, &str); 8] = [
+    ("NODE_IP", "ip"),
+    ("NODE_PORT", "port"),
+    ("NODE_PEERS_FILE", "peers_file"),
+    ("NODE_DIFFICULTY", "difficulty"),
+    ("NODE_HTTP_THREADS", "http_threads"),
+    ("NODE_SEED", "seed"),
+    ("NODE_LOG", "log_filter"),
+    ("NODE_LOG_FORMAT", "log_format"),
+];
+
+fn flag_value(args: &[String], flag: &str) -> Result<Option<String>, ConfigError> {
+    match args.iter().position(|a| a == flag) {
+        Some(i) => args
+            .get(i + 1)
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| ConfigError::MissingValue(flag.into())),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let path = std::env::temp_dir().join(format!("p2p-config-{}.json", std::process::id()));
+        fs::write(&path, r#"{"port": 6000, "difficulty": 2, "fanout": 3}"#).unwrap();
+        let path = path.to_str().unwrap().to_string();
+
+        let config = NodeConfig::load(&args(&["--config", &path]), no_env).unwrap();
+        assert_eq!(
+            (config.port, config.difficulty, config.fanout),
+            (6000, 2, 3)
+        );
+        assert_eq!(config.http_threads, NodeConfig::default().http_threads);
+
+        let env = |key: &str| match key {
+            "NODE_CONFIG" => Some(path.clone()),
+            "NODE_PORT" => Some("7000".to_string()),
+            "NODE_DIFFICULTY" => Some("4".to_string()),
+            _ => None,
+        };
+        let config = NodeConfig::load(&[], env).unwrap();
+        assert_eq!(
+            (config.port, config.difficulty, config.fanout),
+            (7000, 4, 3)
+        );
+
+        let config = NodeConfig::load(&args(&["--difficulty", "6", "8000"]), env).unwrap();
+        assert_eq!(
+            (config.port, config.difficulty, config.fanout),
+            (8000, 6, 3)
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bad_flags_and_files_are_reported() {
+        let load = |a: &[&str]| NodeConfig::load(&args(a), no_env).unwrap_err();
+
+        assert!(
+            matches!(load(&["--colour", "red"]), ConfigError::UnknownFlag(f) if f == "--colour")
+        );
+        assert!(matches!(load(&["--port"]), ConfigError::MissingValue(f) if f == "--port"));
+        assert!(matches!(load(&["--config"]), ConfigError::MissingValue(_)));
+        assert!(matches!(
+            load(&["--port", "70000"]),
+            ConfigError::InvalidValue { key, value } if key == "port" && value == "70000"
+        ));
+        assert!(matches!(
+            load(&["--seed", "-1"]),
+            ConfigError::InvalidValue { .. }
+        ));
+        assert!(matches!(load(&["abc"]), ConfigError::InvalidValue { .. }));
+        assert!(matches!(
+            load(&["--config", "/nonexistent/p2p.json"]),
+            ConfigError::Io { .. }
+        ));
+
+        let env = |key: &str| (key == "NODE_HTTP_THREADS").then(|| "many".to_string());
+        assert!(matches!(
+            NodeConfig::load(&[], env).unwrap_err(),
+            ConfigError::InvalidValue { key, .. } if key == "http_threads"
+        ));
+    }
+
+    #[test]
+    fn validate_rejects_values_the_node_cannot_run_with() {
+        assert!(NodeConfig::default().validate().is_ok());
+
+        let invalid: [(&str, &str); 9] = [
+            ("http_max_body_bytes", "0"),
+            ("outbound_slots", "0"),
+            ("inbound_slots", "0"),
+            ("fanout", "0"),
+            ("ping_interval_secs", "0"),
+            ("difficulty", "65"),
+            ("ip", "localhost"),
+            ("log_filter", "p2p=loud"),
+            ("rate_limit_gossip_per_sec", "NaN"),
+        ];
+        for (key, value) in invalid {
+            let mut config = NodeConfig::default();
+            config.set(key, value).unwrap();
+            assert!(config.validate().is_err(), "{} = {}", key, value);
+        }
+
+        // A burst of 0 turns a limit off rather than blocking everything.
+        let mut config = NodeConfig::default();
+        config.set("rate_limit_peers_burst", "0").unwrap();
+        assert!(config.validate().is_ok());
+        assert!(!config.rate_limits().contains_key("peers"));
+    }
+}
@@ -0,0 +1,94 @@
+//! Changes to a node's ledger and peer table, pushed to whoever is
+//! listening, such as the clients of `GET /events`.
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events a slow subscriber may fall behind by before it misses some.
+const EVENT_BUFFER: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum Event {
+    /// A block joined the main chain, either on top of the old tip or as
+    /// part of a reorg.
+    Block {
+        hash: String,
+        height: usize,
+    },
+    /// The main chain switched from the branch ending in `old_tip`,
+    /// dropping `depth` of its blocks.
+    Reorg {
+        old_tip: String,
+        new_tip: String,
+        depth: usize,
+    },
+    /// A new transaction entered the mempool.
+    TxAccepted {
+        hash: String,
+        data: String,
+    },
+    /// A transaction got into a main chain block. Sent again if a reorg
+    /// moves it to another block.
+    TxConfirmed {
+        hash: String,
+        block_hash: String,
+        height: usize,
+    },
+    PeerAdded {
+        ip: String,
+        port: u16,
+    },
+    PeerRemoved {
+        ip: String,
+        port: u16,
+    },
+}
+
+impl Event {
+    /// Every name `name` can return.
+    pub const NAMES: [&'static str; 6] = [
+        "block",
+        "reorg",
+        "tx_accepted",
+        "tx_confirmed",
+        "peer_added",
+        "peer_removed",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Block { .. } => "block",
+            Event::Reorg { .. } => "reorg",
+            Event::TxAccepted { .. } => "tx_accepted",
+            Event::TxConfirmed { .. } => "tx_confirmed",
+            Event::PeerAdded { .. } => "peer_added",
+            Event::PeerRemoved { .. } => "peer_removed",
+        }
+    }
+}
+
+/// Sends events to everyone subscribed at the time. Clones share the same
+/// subscribers; events published while nobody listens are dropped.
+#[derive(Debug, Clone)]
+pub struct Events(broadcast::Sender<Event>);
+
+impl Events {
+    pub fn new() -> Self {
+        Events(broadcast::channel(EVENT_BUFFER).0)
+    }
+
+    pub fn publish(&self, event: Event) {
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
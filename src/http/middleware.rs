@@ -0,0 +1,426 @@
+//! Composable layers around an [`HttpHandler`].
+//!
+//! A [`Middleware`] sees every request before the handler it wraps and the
+//! result after it, so concerns like logging or CORS can be stacked onto a
+//! handler without touching it:
+//!
+//! ```ignore
+//! let handler = RequestHandler::new(node)
+//!     .layer(CatchPanic)
+//!     .layer(Cors::default())
+//!     .layer(Logger);
+//! ```
+//!
+//! The last layer added is the outermost one and runs first.
+
+use crate::http::server::{HttpHandler, HttpRequest, HttpResult};
+use crate::sync::lock;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+pub trait Middleware: Send + Sync + 'static {
+    /// Handles `request`, usually by passing it on to `next` and adjusting
+    /// the result, or by answering it without calling `next` at all.
+    fn handle(&self, request: HttpRequest, next: &dyn HttpHandler) -> HttpResult;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(HttpRequest, &dyn HttpHandler) -> HttpResult + Send + Sync + 'static,
+{
+    fn handle(&self, request: HttpRequest, next: &dyn HttpHandler) -> HttpResult {
+        self(request, next)
+    }
+}
+
+/// Handler `inner` wrapped in `middleware`.
+pub struct Layered<M, H> {
+    middleware: M,
+    inner: H,
+}
+
+impl<M: Middleware, H: HttpHandler> HttpHandler for Layered<M, H> {
+    fn handle(&self, request: HttpRequest) -> HttpResult {
+        self.middleware.handle(request, &self.inner)
+    }
+}
+
+pub trait HttpHandlerExt: HttpHandler + Sized {
+    fn layer<M: Middleware>(self, middleware: M) -> Layered<M, Self> {
+        Layered {
+            middleware,
+            inner: self,
+        }
+    }
+}
+
+impl<H: HttpHandler> HttpHandlerExt for H {}
+
+/// Logs every request with its status and how long it took.
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn handle(&self, request: HttpRequest, next: &dyn HttpHandler) -> HttpResult {
+        let method = request.method.clone();
+        let started = Instant::now();
+
+        let result = next.handle(request);
+
+        info!(
+            method = method.name(),
+            path = method.path(),
+            status = result.status,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "handled request"
+        );
+        result
+    }
+}
+
+/// Adds CORS headers so browsers may call the API from other origins.
+pub struct Cors {
+    pub allow_origin: String,
+    pub allow_methods: String,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            allow_origin: "*".into(),
+            allow_methods: "GET, HEAD, POST, OPTIONS".into(),
+        }
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: HttpRequest, next: &dyn HttpHandler) -> HttpResult {
+        let mut result = next.handle(request);
+
+        // Preflight answers already list the methods of their own path.
+        result
+            .headers
+            .entry("Access-Control-Allow-Methods".into())
+            .or_insert_with(|| self.allow_methods.clone());
+
+        result.with_header("Access-Control-Allow-Origin", self.allow_origin.as_str())
+    }
+}
+
+/// Turns a panicking handler into a `500` response instead of losing the
+/// request. Only works when panics unwind.
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    fn handle(&self, request: HttpRequest, next: &dyn HttpHandler) -> HttpResult {
+        let method = request.method.clone();
+
+        panic::catch_unwind(AssertUnwindSafe(|| next.handle(request))).unwrap_or_else(|_| {
+            error!(
+                method = method.name(),
+                path = method.path(),
+                "handler panicked"
+            );
+            HttpResult::err(500, "Internal server error")
+        })
+    }
+}
+
+/// Token bucket refill settings of one route class.
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    /// Requests a client may send at once after being quiet for a while.
+    pub burst: u32,
+    /// Sustained requests per second.
+    pub per_sec: f64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Buckets tracked before some are forgotten.
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+/// Buckets left after forgetting some, so the next sweep is at least a
+/// quarter of the limit of new clients away.
+const KEPT_BUCKETS: usize = MAX_TRACKED_BUCKETS / 4 * 3;
+
+type Classifier = Box<dyn Fn(&HttpRequest) -> Option<&'static str> + Send + Sync>;
+
+/// Limits how often each client IP may call each class of routes, answering
+/// `429 Too Many Requests` with `Retry-After` once its bucket is empty.
+///
+/// `classify` names the class of a request; requests without a class, a
+/// configured rate or a known remote address are not limited. Neither are
+/// loopback clients, which are usually other nodes on the same machine and
+/// would otherwise all share one bucket.
+pub struct RateLimit {
+    rates: HashMap<&'static str, Rate>,
+    classify: Classifier,
+    buckets: Mutex<HashMap<(IpAddr, &'static str), Bucket>>,
+}
+
+impl RateLimit {
+    pub fn new(
+        rates: HashMap<&'static str, Rate>,
+        classify: impl Fn(&HttpRequest) -> Option<&'static str> + Send + Sync + 'static,
+    ) -> Self {
+        RateLimit {
+            rates,
+            classify: Box::new(classify),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the client's bucket, or returns how long until
+    /// one is available.
+    fn acquire(&self, ip: IpAddr, class: &'static str, rate: Rate) -> Result<(), Duration> {
+        let now = Instant::now();
+        let burst = rate.burst as f64;
+        let mut buckets = lock(&self.buckets);
+
+        if buckets.len() >= MAX_TRACKED_BUCKETS && !buckets.contains_key(&(ip, class)) {
+            self.forget_buckets(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry((ip, class)).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate.per_sec).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / rate.per_sec,
+            ))
+        }
+    }
+
+    /// Shrinks `buckets` to `KEPT_BUCKETS`. Buckets that have refilled
+    /// completely hold no information and go first; if that isn't enough,
+    /// the ones left alone the longest follow and their clients start over
+    /// with a full burst.
+    fn forget_buckets(&self, buckets: &mut HashMap<(IpAddr, &'static str), Bucket>, now: Instant) {
+        buckets.retain(|(_, class), bucket| {
+            let rate = self.rates[class];
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * rate.per_sec < rate.burst as f64
+        });
+
+        if buckets.len() > KEPT_BUCKETS {
+            let mut by_age: Vec<_> = buckets.iter().map(|(&key, b)| (b.updated, key)).collect();
+            let excess = by_age.len() - KEPT_BUCKETS;
+            by_age.select_nth_unstable_by_key(excess, |&(updated, _)| updated);
+            for (_, key) in &by_age[..excess] {
+                buckets.remove(key);
+            }
+        }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, request: HttpRequest, next: &dyn HttpHandler) -> HttpResult {
+        let ip = request
+            .remote_addr
+            .as_deref()
+            .and_then(|addr| addr.parse::<SocketAddr>().ok())
+            .map(|addr| addr.ip().to_canonical())
+            .filter(|ip| !ip.is_loopback());
+
+        let limit = (self.classify)(&request)
+            .and_then(|class| Some((class, *self.rates.get(class)?)))
+            .zip(ip);
+
+        let acquired = match limit {
+            Some(((class, rate), ip)) => self.acquire(ip, class, rate).inspect_err(|_| {
+                warn!(client = %ip, class, "rate limited");
+            }),
+            None => Ok(()),
+        };
+
+        match acquired {
+            Ok(()) => next.handle(request),
+            Err(wait) => {
+                let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+                HttpResult::err(429, "Too many requests")
+                    .with_header("Retry-After", retry_after.to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::server::HttpMethod;
+    use std::thread;
+
+    struct NoContent;
+
+    impl HttpHandler for NoContent {
+        fn handle(&self, _request: HttpRequest) -> HttpResult {
+            HttpResult::empty(204)
+        }
+    }
+
+    fn request(path: &str, client: Option<&str>) -> HttpRequest {
+        let mut request = HttpRequest::new(HttpMethod::POST(path.into()), String::new());
+        request.remote_addr = client.map(str::to_string);
+        request
+    }
+
+    /// Limits `/limited` to a burst of 2 refilling at 20 per second.
+    fn limited() -> impl HttpHandler {
+        let rates = HashMap::from([(
+            "test",
+            Rate {
+                burst: 2,
+                per_sec: 20.0,
+            },
+        )]);
+        NoContent.layer(RateLimit::new(rates, |request| {
+            (request.method.path() == "/limited").then_some("test")
+        }))
+    }
+
+    #[test]
+    fn buckets_empty_after_the_burst_and_refill_over_time() {
+        let handler = limited();
+        let status = || {
+            handler
+                .handle(request("/limited", Some("10.0.0.1:1")))
+                .status
+        };
+
+        assert_eq!((status(), status()), (204, 204));
+        let limited = handler.handle(request("/limited", Some("10.0.0.1:2")));
+        assert_eq!(limited.status, 429);
+        assert_eq!(limited.headers["Retry-After"], "1");
+
+        // A token comes back every 50ms.
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!((status(), status()), (204, 429));
+
+        // Refilling stops at the burst size.
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!((status(), status(), status()), (204, 204, 429));
+    }
+
+    #[test]
+    fn each_client_has_its_own_bucket_and_other_routes_are_not_limited() {
+        let handler = limited();
+        let status = |path, client| handler.handle(request(path, client)).status;
+
+        for _ in 0..2 {
+            assert_eq!(status("/limited", Some("10.0.0.1:1")), 204);
+        }
+        assert_eq!(status("/limited", Some("10.0.0.1:1")), 429);
+        assert_eq!(status("/limited", Some("10.0.0.2:1")), 204);
+        assert_eq!(status("/other", Some("10.0.0.1:1")), 204);
+        assert_eq!(status("/limited", None), 204);
+    }
+
+    #[test]
+    fn loopback_clients_are_not_limited() {
+        let handler = limited();
+
+        for client in ["127.0.0.1:5000", "[::1]:5001", "[::ffff:127.0.0.1]:5002"] {
+            for _ in 0..5 {
+                assert_eq!(
+                    handler.handle(request("/limited", Some(client))).status,
+                    204
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tracked_buckets_stay_bounded() {
+        let rates = HashMap::from([(
+            "test",
+            Rate {
+                burst: 2,
+                per_sec: 0.001,
+            },
+        )]);
+        let limit = RateLimit::new(rates, |_| Some("test"));
+        let rate = limit.rates["test"];
+        let ip = |i: usize| IpAddr::from([10, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+
+        // None of these refill in time to be forgotten for being full.
+        for i in 0..MAX_TRACKED_BUCKETS {
+            limit.acquire(ip(i), "test", rate).unwrap();
+        }
+        assert_eq!(lock(&limit.buckets).len(), MAX_TRACKED_BUCKETS);
+
+        // The next new client makes room by forgetting the oldest buckets,
+        // and the clients after it fit without another sweep.
+        limit
+            .acquire(ip(MAX_TRACKED_BUCKETS), "test", rate)
+            .unwrap();
+        let buckets = lock(&limit.buckets);
+        assert_eq!(buckets.len(), KEPT_BUCKETS + 1);
+        assert!(!buckets.contains_key(&(ip(0), "test")));
+        assert!(buckets.contains_key(&(ip(MAX_TRACKED_BUCKETS - 1), "test")));
+        drop(buckets);
+
+        // A known client keeps its bucket, and its empty one at that.
+        let last = ip(MAX_TRACKED_BUCKETS);
+        assert!(limit.acquire(last, "test", rate).is_ok());
+        assert!(limit.acquire(last, "test", rate).is_err());
+    }
+
+    /// Appends `name` to the `Trace` header on the way out.
+    fn tag(name: &'static str) -> impl Middleware {
+        move |request: HttpRequest, next: &dyn HttpHandler| {
+            let mut result = next.handle(request);
+            let trace = result.headers.remove("Trace").unwrap_or_default();
+            result.with_header("Trace", format!("{}{}", trace, name))
+        }
+    }
+
+    #[test]
+    fn last_layer_added_runs_outermost() {
+        let handler = NoContent.layer(tag("a")).layer(tag("b"));
+
+        let result = handler.handle(request("/", None));
+        assert_eq!(result.headers["Trace"], "ab");
+    }
+
+    #[test]
+    fn panicking_handler_becomes_500() {
+        let handler = NoContent
+            .layer(|_: HttpRequest, _: &dyn HttpHandler| -> HttpResult { panic!("boom") })
+            .layer(CatchPanic);
+
+        assert_eq!(handler.handle(request("/", None)).status, 500);
+    }
+
+    #[test]
+    fn cors_keeps_the_methods_of_preflight_answers() {
+        let handler = NoContent.layer(Cors::default());
+        let result = handler.handle(request("/", None));
+        assert_eq!(result.headers["Access-Control-Allow-Origin"], "*");
+        assert_eq!(
+            result.headers["Access-Control-Allow-Methods"],
+            "GET, HEAD, POST, OPTIONS"
+        );
+
+        let preflight = NoContent
+            .layer(|_: HttpRequest, _: &dyn HttpHandler| HttpResult::options(&["GET"]))
+            .layer(Cors::default());
+        let result = preflight.handle(request("/", None));
+        assert_eq!(result.headers["Access-Control-Allow-Methods"], "GET");
+    }
+}
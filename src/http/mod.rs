@@ -1,2 +1,3 @@
+pub mod middleware;
 pub mod server;
-mod threadpool;
+pub mod threadpool;
@@ -1,19 +1,61 @@
-use crate::http::threadpool::ThreadPool;
+use crate::http::threadpool::{PoolMetrics, ThreadPool};
 use serde::Serialize;
 use serde_json::json;
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Semaphore, mpsc, oneshot, watch};
+use tokio::time::{Duration, Instant, timeout, timeout_at};
+use tracing::{Instrument, Span, debug, error, info, info_span, warn};
 
 pub trait HttpHandler: Send + Sync + 'static {
     fn handle(&self, request: HttpRequest) -> HttpResult;
 }
 
+/// Response body: either complete up front or produced piece by piece.
+pub enum Body {
+    Full(String),
+    /// Pieces are sent as they arrive using chunked transfer encoding. The
+    /// body ends when the sender is dropped.
+    Stream(mpsc::Receiver<String>),
+}
+
+impl Body {
+    /// Length of the body if it is known before sending it.
+    pub fn known_len(&self) -> Option<usize> {
+        match self {
+            Body::Full(body) => Some(body.len()),
+            Body::Stream(_) => None,
+        }
+    }
+
+    /// Collects the whole body, waiting for a stream to finish.
+    pub async fn into_string(self) -> String {
+        match self {
+            Body::Full(body) => body,
+            Body::Stream(mut rx) => {
+                let mut body = String::new();
+                while let Some(piece) = rx.recv().await {
+                    body.push_str(&piece);
+                }
+                body
+            }
+        }
+    }
+}
+
 pub struct HttpResult {
     pub status: u16,
-    pub body: String,
+    pub body: Body,
     pub content_type: &'static str,
+    /// Extra response headers, e.g. `Location` or `Cache-Control`.
+    pub headers: BTreeMap<String, String>,
 }
 
 impl HttpResult {
@@ -23,11 +65,49 @@ impl HttpResult {
 
         Self {
             status,
-            body,
+            body: Body::Full(body),
             content_type: "application/json",
+            headers: BTreeMap::new(),
+        }
+    }
+
+    /// JSON response whose body is produced by whoever holds the sending
+    /// half of `rx`.
+    pub fn stream(status: u16, rx: mpsc::Receiver<String>) -> Self {
+        Self {
+            status,
+            body: Body::Stream(rx),
+            content_type: "application/json",
+            headers: BTreeMap::new(),
+        }
+    }
+
+    /// Non-JSON response, e.g. the plain text metrics format.
+    pub fn text(status: u16, content_type: &'static str, body: String) -> Self {
+        Self {
+            status,
+            body: Body::Full(body),
+            content_type,
+            headers: BTreeMap::new(),
+        }
+    }
+
+    /// Response without a body, such as `204 No Content` or
+    /// `304 Not Modified`.
+    pub fn empty(status: u16) -> Self {
+        Self {
+            status,
+            body: Body::Full(String::new()),
+            content_type: "text/plain",
+            headers: BTreeMap::new(),
         }
     }
 
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.insert(name.to_string(), value.into());
+        self
+    }
+
     pub fn ok<T: Serialize>(value: &T) -> Self {
         Self::json(200, value)
     }
@@ -56,104 +136,768 @@ impl HttpResult {
     pub fn not_impl() -> Self {
         Self::err(501, "Not implemented")
     }
+
+    /// `405` for a known path requested with a method it doesn't support.
+    pub fn method_not_allowed(allowed: &[&str]) -> Self {
+        Self::err(405, "Method not allowed").with_header("Allow", allowed.join(", "))
+    }
+
+    /// Answer to an `OPTIONS` request, including CORS preflights.
+    pub fn options(allowed: &[&str]) -> Self {
+        let allowed = allowed.join(", ");
+
+        Self::empty(204)
+            .with_header("Allow", allowed.clone())
+            .with_header("Access-Control-Allow-Methods", allowed)
+            .with_header("Access-Control-Allow-Headers", "Content-Type")
+            .with_header("Access-Control-Max-Age", "86400")
+    }
+}
+
+/// Reason phrase sent after the status code in the status line.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+/// Statuses whose responses never carry a body.
+fn is_bodiless(status: u16) -> bool {
+    (100..200).contains(&status) || status == 204 || status == 304
+}
+
+/// Handler whose responses are produced asynchronously, e.g. by awaiting
+/// other tasks. Connections are always served on the async runtime.
+pub trait AsyncHttpHandler: Send + Sync + 'static {
+    fn handle(&self, request: HttpRequest) -> HttpFuture<'_>;
+}
+
+pub type HttpFuture<'a> = Pin<Box<dyn Future<Output = HttpResult> + Send + 'a>>;
+
+/// Runs a synchronous [`HttpHandler`] on a dedicated thread pool so that
+/// slow handlers never block the runtime's worker threads.
+pub struct Blocking<H: HttpHandler> {
+    handler: Arc<H>,
+    pool: ThreadPool,
+}
+
+impl<H: HttpHandler> Blocking<H> {
+    /// Runs `handler` on `threads` threads with up to `queue_capacity`
+    /// requests waiting for one. Requests beyond that get `503`.
+    pub fn new(handler: H, threads: usize, queue_capacity: usize) -> Self {
+        Blocking {
+            handler: Arc::new(handler),
+            pool: ThreadPool::new(threads, queue_capacity),
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<PoolMetrics> {
+        self.pool.metrics()
+    }
 }
 
-pub fn start<H: HttpHandler>(addr: &str, handler: H) {
-    let listener = match TcpListener::bind(&addr) {
+impl<H: HttpHandler> AsyncHttpHandler for Blocking<H> {
+    fn handle(&self, request: HttpRequest) -> HttpFuture<'_> {
+        let (tx, rx) = oneshot::channel();
+        let handler = Arc::clone(&self.handler);
+        // Keeps the request's ID and client on whatever the handler logs.
+        let span = Span::current();
+
+        let queued = self.pool.execute(move || {
+            let _ = tx.send(span.in_scope(|| handler.handle(request)));
+        });
+
+        if queued.is_err() {
+            warn!("request queue full, rejecting request");
+            let result = HttpResult::err(503, "Server busy").with_header("Retry-After", "1");
+            return Box::pin(async move { result });
+        }
+
+        Box::pin(async move {
+            rx.await
+                .unwrap_or_else(|_| HttpResult::err(500, "Internal server error"))
+        })
+    }
+}
+
+/// Connection handling settings of the server.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// How long a kept-alive connection may sit idle between requests.
+    pub idle_timeout: Duration,
+    /// How long a client may take to send a whole request once it started.
+    pub read_timeout: Duration,
+    /// How long a single write of the response may stall.
+    pub write_timeout: Duration,
+    /// Requests served on one connection before it is closed.
+    pub max_requests_per_connection: usize,
+    /// Connections served at once. Further ones are turned away with `503`.
+    pub max_connections: usize,
+    /// How long a shutdown waits for open requests to finish.
+    pub drain_timeout: Duration,
+    pub limits: RequestLimits,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            idle_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            max_requests_per_connection: 100,
+            max_connections: 1024,
+            drain_timeout: Duration::from_secs(10),
+            limits: RequestLimits::default(),
+        }
+    }
+}
+
+/// Size limits for incoming requests.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    /// Bytes in the request line and headers together.
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+    pub max_body_bytes: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_header_bytes: 8 * 1024,
+            max_headers: 64,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Serves `handler` on `addr` until `shutdown` completes. Then it stops
+/// accepting connections, lets open ones finish the request they are on
+/// and waits up to `drain_timeout` for them to close.
+pub async fn start<H: AsyncHttpHandler>(
+    addr: &str,
+    config: ServerConfig,
+    handler: H,
+    shutdown: impl Future<Output = ()>,
+) -> std::io::Result<()> {
+    let listener = match TcpListener::bind(addr).await {
         Ok(l) => {
-            println!("[SERVER] TCP listener bound successfully\n");
+            info!(addr, "listening");
             l
         }
         Err(e) => {
-            eprintln!("[ERROR] Failed to bind: {}", e);
-            return;
+            error!(addr, error = %e, "failed to bind");
+            return Err(e);
         }
     };
 
-    let pool = ThreadPool::new(32);
     let handler = Arc::new(handler);
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+    let rejections = Arc::new(Semaphore::new(MAX_PENDING_REJECTIONS));
+    let config = Arc::new(config);
+    let (closing_tx, closing) = watch::channel(false);
+    tokio::pin!(shutdown);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+
+        match accepted {
+            Ok((stream, remote_addr)) => {
+                let Ok(permit) = Arc::clone(&connections).try_acquire_owned() else {
+                    // Under a flood even the 503s are capped; the rest are
+                    // just closed.
+                    let Ok(rejection) = Arc::clone(&rejections).try_acquire_owned() else {
+                        debug!(client = %remote_addr, "too many connections, dropping client");
+                        continue;
+                    };
+
+                    let config = Arc::clone(&config);
+                    tokio::spawn(
+                        async move {
+                            reject(stream, remote_addr, config).await;
+                            drop(rejection);
+                        }
+                        .in_current_span(),
+                    );
+                    continue;
+                };
 
-    println!("[SERVER] Waiting for connections...\n");
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
                 let handler = Arc::clone(&handler);
-                pool.execute(|| {
-                    handle_client(stream, handler);
-                });
+                let config = Arc::clone(&config);
+                let closing = closing.clone();
+                tokio::spawn(
+                    async move {
+                        handle_client(stream, remote_addr, handler, config, closing).await;
+                        drop(permit);
+                    }
+                    .in_current_span(),
+                );
             }
-            Err(e) => eprintln!("[ERROR] Accept error: {}", e),
+            Err(e) => error!(error = %e, "accept failed"),
         }
     }
+
+    drop(listener);
+    info!("stopped accepting connections, draining open ones");
+    let _ = closing_tx.send(true);
+
+    // Every connection holds a permit until it closes.
+    let permits = config.max_connections.min(u32::MAX as usize) as u32;
+    if timeout(config.drain_timeout, connections.acquire_many(permits))
+        .await
+        .is_err()
+    {
+        warn!("open connections did not finish in time");
+    }
+
+    Ok(())
 }
 
-fn handle_client<H: HttpHandler>(mut stream: TcpStream, handler: Arc<H>) {
+/// Source of the IDs that tie a request's log events together. Also sent
+/// back in the `X-Request-Id` header.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Connections over `max_connections` that are sent a `503` at a time.
+const MAX_PENDING_REJECTIONS: usize = 64;
+
+/// Turns away a connection over the `max_connections` limit.
+async fn reject(mut stream: TcpStream, remote_addr: SocketAddr, config: Arc<ServerConfig>) {
+    warn!(client = %remote_addr, "too many connections, rejecting client");
+    let result = HttpResult::err(503, "Too many connections").with_header("Retry-After", "1");
+    let _ = HttpResponse::respond(&mut stream, result, &ResponseContext::close(&config)).await;
+}
+
+/// Serves requests on one connection until the client closes it, asks for
+/// it to be closed, stays idle for too long or hits the request limit, or
+/// the server starts `closing`. Pipelined requests are answered in the
+/// order they arrived.
+///
+/// A client has `read_timeout` to send each request and at most
+/// `max_header_bytes` + `max_body_bytes` of it is ever buffered, so slow or
+/// oversized requests can't tie up the server.
+async fn handle_client<H: AsyncHttpHandler>(
+    mut stream: TcpStream,
+    remote_addr: SocketAddr,
+    handler: Arc<H>,
+    config: Arc<ServerConfig>,
+    mut closing: watch::Receiver<bool>,
+) {
     let mut buf = Vec::new();
+    let mut parser = RequestParser::default();
     let mut tmp = [0u8; 4096];
+    let mut served = 0;
+    let mut deadline = None;
 
     loop {
-        match stream.read(&mut tmp) {
-            Ok(0) => return, // client closed
-            Ok(n) => {
-                buf.extend_from_slice(&tmp[..n]);
+        loop {
+            let (mut req, consumed) = match parser.parse(&buf, &config.limits) {
+                Ok(parsed) => parsed,
+                Err(HttpParseError::Incomplete) => break,
+                Err(e) => {
+                    warn!(client = %remote_addr, error = ?e, "could not parse request");
+                    let result = HttpResult::err(e.status(), e.message());
+                    let ctx = ResponseContext::close(&config);
+                    let _ = HttpResponse::respond(&mut stream, result, &ctx).await;
+                    return;
+                }
+            };
+            buf.drain(..consumed);
+            req.remote_addr = Some(remote_addr.to_string());
+            deadline = None;
+            served += 1;
 
-                match HttpRequest::try_from(&buf) {
-                    Ok(req) => {
-                        println!("[SERVER] Received request: {:?}", req.method);
-                        HttpResponse::respond(&mut stream, req, handler);
-                        return;
-                    }
+            let keep_alive = req.keep_alive()
+                && served < config.max_requests_per_connection
+                && !*closing.borrow();
+            let connection = if keep_alive {
+                Connection::KeepAlive {
+                    timeout: config.idle_timeout,
+                    remaining: config.max_requests_per_connection - served,
+                }
+            } else {
+                Connection::Close
+            };
 
-                    Err(HttpParseError::Incomplete) => {
-                        continue;
-                    }
+            // HEAD is answered like GET, without sending the body.
+            let head = match req.method {
+                HttpMethod::HEAD(path) => {
+                    req.method = HttpMethod::GET(path);
+                    true
+                }
+                _ => false,
+            };
 
-                    Err(e) => {
-                        eprintln!("[ERROR] Parse error: {:?}", e);
-                        return;
-                    }
+            let version = req.version;
+            let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+            let span = info_span!("request", id, client = %remote_addr);
+            let result = span.in_scope(|| handler.handle(req)).instrument(span).await;
+            let result = result.with_header("X-Request-Id", id.to_string());
+
+            // HTTP/1.0 has no chunked encoding, so a streamed body can only
+            // be ended by closing the connection.
+            let streamed = matches!(result.body, Body::Stream(_));
+            let (keep_alive, connection) = if streamed && version == HttpVersion::Http10 {
+                (false, Connection::Close)
+            } else {
+                (keep_alive, connection)
+            };
+
+            let ctx = ResponseContext {
+                version,
+                include_body: !head,
+                connection,
+                write_timeout: config.write_timeout,
+            };
+
+            if HttpResponse::respond(&mut stream, result, &ctx)
+                .await
+                .is_err()
+                || !keep_alive
+            {
+                return;
+            }
+        }
+
+        // Between requests the connection is idle and may time out. Once a
+        // request has started, all of it has to arrive before the deadline.
+        let read = if buf.is_empty() {
+            tokio::select! {
+                read = timeout(config.idle_timeout, stream.read(&mut tmp)) => match read {
+                    Ok(read) => read,
+                    Err(_) => return,
+                },
+                _ = closing.wait_for(|&closing| closing) => return,
+            }
+        } else {
+            let deadline = *deadline.get_or_insert_with(|| Instant::now() + config.read_timeout);
+            match timeout_at(deadline, stream.read(&mut tmp)).await {
+                Ok(read) => read,
+                Err(_) => {
+                    warn!(client = %remote_addr, "client too slow to send request");
+                    let result = HttpResult::err(408, "Request timeout");
+                    let ctx = ResponseContext::close(&config);
+                    let _ = HttpResponse::respond(&mut stream, result, &ctx).await;
+                    return;
                 }
             }
+        };
+
+        match read {
+            Ok(0) => return, // client closed
+            Ok(n) => {
+                // Started a new request, start its deadline with its first bytes.
+                if buf.is_empty() {
+                    deadline = Some(Instant::now() + config.read_timeout);
+                }
+                buf.extend_from_slice(&tmp[..n]);
+            }
             Err(_) => return,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum Connection {
+    KeepAlive { timeout: Duration, remaining: usize },
+    Close,
+}
+
+/// What a response needs to know about the request and connection it is
+/// sent on.
+#[derive(Debug, Clone, Copy)]
+pub struct ResponseContext {
+    pub version: HttpVersion,
+    /// Unset for HEAD requests: the headers still describe the body that
+    /// would have been sent.
+    pub include_body: bool,
+    pub connection: Connection,
+    pub write_timeout: Duration,
+}
+
+impl ResponseContext {
+    /// Context for an error response after which the connection is closed.
+    fn close(config: &ServerConfig) -> Self {
+        ResponseContext {
+            version: HttpVersion::Http11,
+            include_body: true,
+            connection: Connection::Close,
+            write_timeout: config.write_timeout,
+        }
+    }
+}
+
 pub struct HttpResponse;
 
 impl HttpResponse {
-    pub fn respond<H: HttpHandler>(stream: &mut TcpStream, req: HttpRequest, handler: Arc<H>) {
-        let result = handler.handle(req);
-
-        let response = format!(
-            "HTTP/1.0 {} OK\r\n\
-            Content-Type: {}\r\n\
-            Content-Length: {}\r\n\
-            Access-Control-Allow-Origin: *\r\n\
-            Access-Control-Allow-Methods: GET, POST\r\n\
-            \r\n\
-            {}",
+    /// Writes `result` to the stream. Streamed bodies are sent chunked to
+    /// HTTP/1.1 clients and as is, followed by closing the connection, to
+    /// HTTP/1.0 ones. Fails if any single write stalls for longer than the
+    /// context's `write_timeout`.
+    pub async fn respond(
+        stream: &mut TcpStream,
+        result: HttpResult,
+        ctx: &ResponseContext,
+    ) -> std::io::Result<()> {
+        let chunked = ctx.version == HttpVersion::Http11;
+        let write_timeout = ctx.write_timeout;
+
+        let mut response = format!(
+            "HTTP/1.1 {} {}\r\n",
             result.status,
-            result.content_type,
-            result.body.len(),
-            result.body,
+            reason_phrase(result.status)
         );
 
-        let _ = stream.write_all(response.as_bytes());
+        let bodiless = is_bodiless(result.status);
+        if !bodiless {
+            response.push_str(&format!("Content-Type: {}\r\n", result.content_type));
+            match result.body.known_len() {
+                Some(len) => response.push_str(&format!("Content-Length: {}\r\n", len)),
+                None if chunked => response.push_str("Transfer-Encoding: chunked\r\n"),
+                None => {}
+            }
+        }
+
+        match ctx.connection {
+            Connection::KeepAlive { timeout, remaining } => response.push_str(&format!(
+                "Connection: keep-alive\r\nKeep-Alive: timeout={}, max={}\r\n",
+                timeout.as_secs(),
+                remaining
+            )),
+            Connection::Close => response.push_str("Connection: close\r\n"),
+        }
+
+        for (name, value) in &result.headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str("\r\n");
+
+        if !ctx.include_body || bodiless {
+            return write_all(stream, response.as_bytes(), write_timeout).await;
+        }
+
+        match result.body {
+            Body::Full(body) => {
+                response.push_str(&body);
+                write_all(stream, response.as_bytes(), write_timeout).await
+            }
+            Body::Stream(mut rx) => {
+                write_all(stream, response.as_bytes(), write_timeout).await?;
+
+                while let Some(piece) = rx.recv().await {
+                    // An empty chunk would end the body early.
+                    if piece.is_empty() {
+                        continue;
+                    }
+
+                    if chunked {
+                        let chunk = format!("{:x}\r\n{}\r\n", piece.len(), piece);
+                        write_all(stream, chunk.as_bytes(), write_timeout).await?;
+                    } else {
+                        write_all(stream, piece.as_bytes(), write_timeout).await?;
+                    }
+                }
+
+                if chunked {
+                    write_all(stream, b"0\r\n\r\n", write_timeout).await?;
+                }
+                Ok(())
+            }
+        }
     }
 }
 
+/// Writes all of `bytes`, failing if the client stops reading for longer
+/// than `write_timeout`.
+async fn write_all(
+    stream: &mut TcpStream,
+    bytes: &[u8],
+    write_timeout: Duration,
+) -> std::io::Result<()> {
+    timeout(write_timeout, stream.write_all(bytes))
+        .await
+        .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+}
+
 #[derive(Debug, Clone)]
 pub enum HttpMethod {
     GET(String),
+    HEAD(String),
     POST(String),
     PUT(String),
     DELETE(String),
+    OPTIONS(String),
+}
+
+impl HttpMethod {
+    pub fn path(&self) -> &str {
+        match self {
+            HttpMethod::GET(path)
+            | HttpMethod::HEAD(path)
+            | HttpMethod::POST(path)
+            | HttpMethod::PUT(path)
+            | HttpMethod::DELETE(path)
+            | HttpMethod::OPTIONS(path) => path,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HttpMethod::GET(_) => "GET",
+            HttpMethod::HEAD(_) => "HEAD",
+            HttpMethod::POST(_) => "POST",
+            HttpMethod::PUT(_) => "PUT",
+            HttpMethod::DELETE(_) => "DELETE",
+            HttpMethod::OPTIONS(_) => "OPTIONS",
+        }
+    }
+}
+
+/// Decoded query string parameters of a request.
+#[derive(Debug, Clone, Default)]
+pub struct Query(HashMap<String, String>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidParam(pub String);
+
+impl Query {
+    /// Parses `a=1&b=two`, decoding `%XX` escapes and `+` as space.
+    /// Parameters that don't decode are skipped.
+    pub fn parse(query: &str) -> Self {
+        let params = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter_map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                let decode = |s: &str| percent_decode(&s.replace('+', " "));
+                Some((decode(key)?, decode(value)?))
+            })
+            .collect();
+
+        Query(params)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    /// Typed parameter: `Ok(None)` if it is missing, an error if it doesn't
+    /// parse as `T`.
+    pub fn parse_param<T: FromStr>(&self, key: &str) -> Result<Option<T>, InvalidParam> {
+        self.get(key)
+            .map(|v| v.parse().map_err(|_| InvalidParam(key.to_string())))
+            .transpose()
+    }
+}
+
+/// Parameters captured from a path by a pattern such as `/blocks/{hash}`.
+#[derive(Debug, Clone, Default)]
+pub struct PathParams(Vec<(&'static str, String)>);
+
+impl PathParams {
+    /// Matches `path` against `pattern` segment by segment. `{name}`
+    /// segments match any single, non-empty segment, which is captured
+    /// URL-decoded; other segments must match exactly.
+    pub fn match_pattern(pattern: &'static str, path: &str) -> Option<Self> {
+        let mut patterns = pattern.split('/');
+        let mut segments = path.split('/');
+        let mut params = Vec::new();
+
+        loop {
+            match (patterns.next(), segments.next()) {
+                (None, None) => return Some(PathParams(params)),
+                (Some(p), Some(s)) => {
+                    let s = percent_decode(s)?;
+                    match p.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                        Some(_) if s.is_empty() => return None,
+                        Some(name) => params.push((name, s)),
+                        None if p == s => {}
+                        None => return None,
+                    }
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// Typed parameter, `None` if it is missing or doesn't parse as `T`.
+    pub fn get<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.0
+            .iter()
+            .find(|(n, _)| *n == name)
+            .and_then(|(_, v)| v.parse().ok())
+    }
+}
+
+/// Builds a path from a pattern, URL-encoding the given parameter values.
+pub fn fill_pattern(pattern: &str, params: &[(&str, String)]) -> String {
+    pattern
+        .split('/')
+        .map(|p| {
+            let name = p.strip_prefix('{').and_then(|p| p.strip_suffix('}'));
+            match name.and_then(|name| params.iter().find(|(n, _)| *n == name)) {
+                Some((_, value)) => percent_encode(value),
+                None => p.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Splits a request target into its path and query string.
+pub fn split_target(target: &str) -> (&str, Query) {
+    match target.split_once('?') {
+        Some((path, query)) => (path, Query::parse(query)),
+        None => (target, Query::default()),
+    }
+}
+
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(out).ok()
+}
+
+pub fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Declares a route enum whose parsing and `to_path` come from one table of
+/// `METHOD "pattern" => Variant` entries. Path parameters are named in the
+/// variant and parsed with `FromStr`:
+///
+/// ```ignore
+/// routes! {
+///     pub enum Route {
+///         GET "/status" => GetStatus,
+///         GET "/blocks/{hash}" => GetBlock(hash: String),
+///     }
+/// }
+/// ```
+///
+/// Routes are tried in order, so list fixed paths before overlapping
+/// patterns.
+#[macro_export]
+macro_rules! routes {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $(
+                $method:ident $pattern:literal => $variant:ident $( ( $($field:ident : $ty:ty),* $(,)? ) )?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $( $variant $( ( $($ty),* ) )? ),*
+        }
+
+        impl $name {
+            pub fn parse(method: &$crate::http::server::HttpMethod) -> Option<Self> {
+                $(
+                    let route = $crate::http::server::PathParams::match_pattern($pattern, method.path())
+                        .filter(|_| method.name() == stringify!($method))
+                        .and_then(|_params| {
+                            Some($name::$variant $( ( $( _params.get::<$ty>(stringify!($field))? ),* ) )?)
+                        });
+                    if route.is_some() {
+                        return route;
+                    }
+                )*
+                None
+            }
+
+            pub fn to_path(&self) -> String {
+                match self {
+                    $(
+                        $name::$variant $( ( $($field),* ) )? => $crate::http::server::fill_pattern(
+                            $pattern,
+                            &[ $( $( (stringify!($field), $field.to_string()) ),* )? ],
+                        ),
+                    )*
+                }
+            }
+
+            /// Method the route was declared with.
+            pub fn method(&self) -> &'static str {
+                match self {
+                    $( $name::$variant { .. } => stringify!($method), )*
+                }
+            }
+
+            /// Pattern the route was declared with, e.g. `/blocks/{hash}`.
+            /// Unlike `to_path` it is the same for every request to the
+            /// route, which makes it a good label.
+            pub fn pattern(&self) -> &'static str {
+                match self {
+                    $( $name::$variant { .. } => $pattern, )*
+                }
+            }
+        }
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
 }
 
 #[derive(Debug)]
 pub struct HttpRequest {
+    /// Method and path, without the query string.
     pub method: HttpMethod,
+    pub query: Query,
+    pub version: HttpVersion,
     pub headers: HashMap<String, String>,
     pub body: String,
     pub remote_addr: Option<String>,
@@ -165,42 +909,127 @@ pub enum HttpParseError {
     MissingRequestLine,
     InvalidRequestLine,
     UnsupportedMethod(String),
+    UnsupportedVersion(String),
     InvalidHeaderLine,
     MissingContentLength,
     InvalidContentLength,
     ContentLengthMismatch { expected: usize, actual: usize },
+    InvalidChunk,
+    HeadersTooLarge,
+    TooManyHeaders,
+    BodyTooLarge,
     Incomplete,
 }
 
+impl HttpParseError {
+    /// Status to answer a request that failed to parse with.
+    pub fn status(&self) -> u16 {
+        match self {
+            HttpParseError::HeadersTooLarge | HttpParseError::TooManyHeaders => 431,
+            HttpParseError::BodyTooLarge => 413,
+            HttpParseError::UnsupportedMethod(_) => 501,
+            HttpParseError::UnsupportedVersion(_) => 505,
+            _ => 400,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            HttpParseError::HeadersTooLarge => "Request headers too large",
+            HttpParseError::TooManyHeaders => "Too many request headers",
+            HttpParseError::BodyTooLarge => "Request body too large",
+            HttpParseError::UnsupportedMethod(_) => "Not implemented",
+            HttpParseError::UnsupportedVersion(_) => "HTTP version not supported",
+            _ => "Bad request",
+        }
+    }
+}
+
 impl HttpRequest {
+    /// HTTP/1.1 request with the given body, as if received from a client.
+    /// The method's path may carry a query string.
+    pub fn new(method: HttpMethod, body: String) -> Self {
+        let (path, query) = split_target(method.path());
+        let path = path.to_string();
+
+        let method = match method {
+            HttpMethod::GET(_) => HttpMethod::GET(path),
+            HttpMethod::HEAD(_) => HttpMethod::HEAD(path),
+            HttpMethod::POST(_) => HttpMethod::POST(path),
+            HttpMethod::PUT(_) => HttpMethod::PUT(path),
+            HttpMethod::DELETE(_) => HttpMethod::DELETE(path),
+            HttpMethod::OPTIONS(_) => HttpMethod::OPTIONS(path),
+        };
+
+        HttpRequest {
+            method,
+            query,
+            version: HttpVersion::Http11,
+            headers: HashMap::from([("content-length".into(), body.len().to_string())]),
+            body,
+            remote_addr: None,
+        }
+    }
+
+    /// Parses a buffer holding exactly one request.
     pub fn try_from(buf: &[u8]) -> Result<HttpRequest, HttpParseError> {
-        let text = std::str::from_utf8(buf).map_err(|_| HttpParseError::InvalidUtf8)?;
+        let (req, consumed) = Self::parse(buf, &RequestLimits::default())?;
 
-        let (head, body) = text
-            .split_once("\r\n\r\n")
-            .ok_or(HttpParseError::Incomplete)?;
+        if consumed < buf.len() {
+            return Err(HttpParseError::ContentLengthMismatch {
+                expected: req.body.len(),
+                actual: req.body.len() + buf.len() - consumed,
+            });
+        }
+
+        Ok(req)
+    }
 
+    /// Parses the first request in `buf` and returns it together with the
+    /// number of bytes it took up. Anything after that belongs to the next,
+    /// pipelined request.
+    ///
+    /// Requests over `limits` are rejected as soon as that is known, without
+    /// waiting for the rest of them.
+    pub fn parse(
+        buf: &[u8],
+        limits: &RequestLimits,
+    ) -> Result<(HttpRequest, usize), HttpParseError> {
+        RequestParser::default().parse(buf, limits)
+    }
+
+    /// Parses the request line and headers, leaving the body empty.
+    fn parse_head(head: &str, limits: &RequestLimits) -> Result<HttpRequest, HttpParseError> {
         let mut lines = head.lines();
         let request_line = lines.next().ok_or(HttpParseError::MissingRequestLine)?;
 
         let mut parts = request_line.split_whitespace();
         let method_str = parts.next().ok_or(HttpParseError::InvalidRequestLine)?;
-        let path = parts
-            .next()
-            .ok_or(HttpParseError::InvalidRequestLine)?
-            .to_string();
-        let _version = parts.next().ok_or(HttpParseError::InvalidRequestLine)?;
+        let target = parts.next().ok_or(HttpParseError::InvalidRequestLine)?;
+        let (path, query) = split_target(target);
+        let path = path.to_string();
+        let version = match parts.next().ok_or(HttpParseError::InvalidRequestLine)? {
+            "HTTP/1.0" => HttpVersion::Http10,
+            "HTTP/1.1" => HttpVersion::Http11,
+            other => return Err(HttpParseError::UnsupportedVersion(other.to_string())),
+        };
 
         let method = match method_str {
             "GET" => HttpMethod::GET(path),
+            "HEAD" => HttpMethod::HEAD(path),
             "POST" => HttpMethod::POST(path),
             "PUT" => HttpMethod::PUT(path),
             "DELETE" => HttpMethod::DELETE(path),
+            "OPTIONS" => HttpMethod::OPTIONS(path),
             other => return Err(HttpParseError::UnsupportedMethod(other.to_string())),
         };
 
         let mut headers = HashMap::new();
-        for line in lines {
+        for (i, line) in lines.enumerate() {
+            if i == limits.max_headers {
+                return Err(HttpParseError::TooManyHeaders);
+            }
+
             let (key, value) = line
                 .split_once(':')
                 .ok_or(HttpParseError::InvalidHeaderLine)?;
@@ -211,30 +1040,652 @@ impl HttpRequest {
             );
         }
 
-        let expected_len = headers
+        Ok(HttpRequest {
+            method,
+            query,
+            version,
+            headers,
+            body: String::new(),
+            remote_addr: None,
+        })
+    }
+
+    /// How the body following the headers is delimited.
+    fn framing(&self, limits: &RequestLimits) -> Result<Framing, HttpParseError> {
+        let chunked = self
+            .headers
+            .get("transfer-encoding")
+            .is_some_and(|te| te.eq_ignore_ascii_case("chunked"));
+        if chunked {
+            return Ok(Framing::Chunked(Chunks::default()));
+        }
+
+        let len = self
+            .headers
             .get("content-length")
-            .map(|s| s.parse::<usize>().map_err(|_| HttpParseError::InvalidContentLength))
+            .map(|s| {
+                s.parse::<usize>()
+                    .map_err(|_| HttpParseError::InvalidContentLength)
+            })
             .transpose()?
             .unwrap_or(0);
 
-        let actual_len = body.as_bytes().len();
+        if len > limits.max_body_bytes {
+            return Err(HttpParseError::BodyTooLarge);
+        }
+
+        Ok(Framing::Length(len))
+    }
+
+    /// Whether the client wants the connection kept open after this
+    /// request: the default for HTTP/1.1 unless it sent `Connection: close`,
+    /// and opt-in via `Connection: keep-alive` for HTTP/1.0.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self
+            .headers
+            .get("connection")
+            .map(|v| v.to_ascii_lowercase());
 
-        if actual_len < expected_len {
-            return Err(HttpParseError::Incomplete);
+        match self.version {
+            HttpVersion::Http11 => connection.as_deref() != Some("close"),
+            HttpVersion::Http10 => connection.as_deref() == Some("keep-alive"),
         }
+    }
+}
 
-        if actual_len > expected_len {
-            return Err(HttpParseError::ContentLengthMismatch {
-                expected: expected_len,
-                actual: actual_len,
-            });
+/// Parses the requests of one connection as they arrive. What has been
+/// worked out about a partial request is kept between reads, so each byte
+/// is only looked at once however thinly the client spreads it out.
+#[derive(Default)]
+struct RequestParser {
+    state: ParseState,
+}
+
+enum ParseState {
+    /// Looking for the end of the head, which isn't in the first `scanned`
+    /// bytes.
+    Head { scanned: usize },
+    /// Waiting for the body, which starts at `body_start`.
+    Body {
+        request: Box<HttpRequest>,
+        body_start: usize,
+        framing: Framing,
+    },
+}
+
+impl Default for ParseState {
+    fn default() -> Self {
+        ParseState::Head { scanned: 0 }
+    }
+}
+
+enum Framing {
+    Length(usize),
+    Chunked(Chunks),
+}
+
+impl RequestParser {
+    /// `HttpRequest::parse`, picking up where the last call on the same
+    /// buffer left off. `buf` may only have grown since then; once a request
+    /// is returned the parser starts over for the next one.
+    fn parse(
+        &mut self,
+        buf: &[u8],
+        limits: &RequestLimits,
+    ) -> Result<(HttpRequest, usize), HttpParseError> {
+        if let ParseState::Head { scanned } = &mut self.state {
+            // The end of the head may straddle the old end of the buffer.
+            let from = scanned.saturating_sub(3);
+            let head_end = match buf[from..].windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(i) if from + i > limits.max_header_bytes => {
+                    return Err(HttpParseError::HeadersTooLarge);
+                }
+                Some(i) => from + i,
+                None if buf.len() > limits.max_header_bytes + 3 => {
+                    return Err(HttpParseError::HeadersTooLarge);
+                }
+                None => {
+                    *scanned = buf.len();
+                    return Err(HttpParseError::Incomplete);
+                }
+            };
+
+            let head =
+                std::str::from_utf8(&buf[..head_end]).map_err(|_| HttpParseError::InvalidUtf8)?;
+            let request = HttpRequest::parse_head(head, limits)?;
+            let framing = request.framing(limits)?;
+            self.state = ParseState::Body {
+                request: Box::new(request),
+                body_start: head_end + 4,
+                framing,
+            };
         }
 
-        Ok(HttpRequest {
-            method,
-            headers,
-            body: body.to_string(),
-            remote_addr: None,
-        })
+        let ParseState::Body {
+            body_start,
+            framing,
+            ..
+        } = &mut self.state
+        else {
+            unreachable!("the head was parsed above");
+        };
+        let body_start = *body_start;
+
+        let (body, body_end) = match framing {
+            Framing::Length(len) => {
+                let body_end = body_start + *len;
+                if buf.len() < body_end {
+                    return Err(HttpParseError::Incomplete);
+                }
+                (buf[body_start..body_end].to_vec(), body_end)
+            }
+            Framing::Chunked(chunks) => {
+                let len = chunks.decode(&buf[body_start..], limits)?;
+                (std::mem::take(&mut chunks.body), body_start + len)
+            }
+        };
+
+        let ParseState::Body { mut request, .. } = std::mem::take(&mut self.state) else {
+            unreachable!("the head was parsed above");
+        };
+        request.body = String::from_utf8(body).map_err(|_| HttpParseError::InvalidUtf8)?;
+
+        Ok((*request, body_end))
+    }
+}
+
+/// Progress through a chunked request body.
+#[derive(Default)]
+struct Chunks {
+    body: Vec<u8>,
+    /// Start of the next size line, or trailer line once `trailers` is set.
+    pos: usize,
+    /// Bytes from `pos` on known not to end the line.
+    scanned: usize,
+    /// Where the trailers started, once the last chunk has been read.
+    trailers: Option<usize>,
+}
+
+impl Chunks {
+    /// Decodes what has arrived of the chunked body at the start of `buf`.
+    /// Once the body is complete, returns the number of bytes it took up,
+    /// including the last chunk and any (ignored) trailers.
+    fn decode(&mut self, buf: &[u8], limits: &RequestLimits) -> Result<usize, HttpParseError> {
+        loop {
+            if let Some(start) = self.trailers {
+                if self.pos - start > limits.max_header_bytes {
+                    return Err(HttpParseError::HeadersTooLarge);
+                }
+
+                // Trailers end with an empty line.
+                let end = self.line_end(buf, limits)?;
+                let empty = end == self.pos;
+                self.advance(end + 2);
+                if empty {
+                    return Ok(self.pos);
+                }
+                continue;
+            }
+
+            let end = self.line_end(buf, limits)?;
+            let size_line = std::str::from_utf8(&buf[self.pos..end])
+                .map_err(|_| HttpParseError::InvalidChunk)?;
+            let size = size_line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16).map_err(|_| HttpParseError::InvalidChunk)?;
+
+            if size == 0 {
+                self.advance(end + 2);
+                self.trailers = Some(self.pos);
+                continue;
+            }
+
+            if self.body.len().saturating_add(size) > limits.max_body_bytes {
+                return Err(HttpParseError::BodyTooLarge);
+            }
+
+            let data_start = end + 2;
+            let data_end = data_start + size;
+            if buf.len() < data_end + 2 {
+                return Err(HttpParseError::Incomplete);
+            }
+            if &buf[data_end..data_end + 2] != b"\r\n" {
+                return Err(HttpParseError::InvalidChunk);
+            }
+
+            self.body.extend_from_slice(&buf[data_start..data_end]);
+            self.advance(data_end + 2);
+        }
+    }
+
+    fn advance(&mut self, pos: usize) {
+        self.pos = pos;
+        self.scanned = 0;
+    }
+
+    /// End of the line starting at `pos`. Size lines and trailers are
+    /// bounded like headers, chunk data by the body limit, so a partial body
+    /// never grows past those.
+    fn line_end(&mut self, buf: &[u8], limits: &RequestLimits) -> Result<usize, HttpParseError> {
+        // A `\r` at the end of what was scanned may start the line break.
+        let from = self.pos + self.scanned.saturating_sub(1);
+
+        match buf[from..].windows(2).position(|w| w == b"\r\n") {
+            Some(i) if from + i - self.pos > limits.max_header_bytes => {
+                Err(HttpParseError::HeadersTooLarge)
+            }
+            Some(i) => {
+                self.scanned = from + i - self.pos;
+                Ok(from + i)
+            }
+            None if buf.len() - self.pos > limits.max_header_bytes + 1 => {
+                Err(HttpParseError::HeadersTooLarge)
+            }
+            None => {
+                self.scanned = buf.len() - self.pos;
+                Err(HttpParseError::Incomplete)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers with the path and body of the request.
+    struct Echo;
+
+    impl AsyncHttpHandler for Echo {
+        fn handle(&self, request: HttpRequest) -> HttpFuture<'_> {
+            let body = format!("{} {}", request.method.path(), request.body);
+            Box::pin(async move { HttpResult::text(200, "text/plain", body) })
+        }
+    }
+
+    /// Serves a single connection on which `request` is sent and returns
+    /// everything the server wrote until it closed the connection.
+    async fn exchange(config: ServerConfig, request: &[u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, remote_addr) = listener.accept().await.unwrap();
+
+        let (_closing_tx, closing) = watch::channel(false);
+        let config = Arc::new(config);
+        let server = tokio::spawn(handle_client(
+            stream,
+            remote_addr,
+            Arc::new(Echo),
+            config,
+            closing,
+        ));
+
+        client.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        server.await.unwrap();
+
+        String::from_utf8(response).unwrap()
+    }
+
+    fn parse(buf: &str) -> Result<(HttpRequest, usize), HttpParseError> {
+        HttpRequest::parse(buf.as_bytes(), &RequestLimits::default())
+    }
+
+    #[test]
+    fn parse_stops_at_the_end_of_the_first_pipelined_request() {
+        let first = "POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc";
+        let buf = format!("{}GET /b HTTP/1.1\r\n\r\n", first);
+
+        let (req, consumed) = parse(&buf).unwrap();
+        assert_eq!(req.method.path(), "/a");
+        assert_eq!(req.body, "abc");
+        assert_eq!(consumed, first.len());
+
+        let (req, _) = parse(&buf[consumed..]).unwrap();
+        assert_eq!(req.method.path(), "/b");
+        assert!(matches!(
+            parse("GET /c HTTP/1.1\r\n"),
+            Err(HttpParseError::Incomplete)
+        ));
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_answered_in_order_on_one_connection() {
+        let requests = "GET /one HTTP/1.1\r\n\r\n\
+                        POST /two HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody\
+                        GET /three HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+        let response = exchange(ServerConfig::default(), requests.as_bytes()).await;
+
+        let answers: Vec<&str> = response.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(answers.len(), 3, "{}", response);
+        assert!(answers[0].contains("Keep-Alive: timeout=5, max=99"));
+        assert!(answers[0].ends_with("\r\n\r\n/one "));
+        assert!(answers[1].contains("Keep-Alive: timeout=5, max=98"));
+        assert!(answers[1].ends_with("\r\n\r\n/two body"));
+        assert!(answers[2].contains("Connection: close"));
+        assert!(answers[2].ends_with("\r\n\r\n/three "));
+    }
+
+    #[tokio::test]
+    async fn connection_closes_after_the_last_request_allowed() {
+        let config = ServerConfig {
+            max_requests_per_connection: 2,
+            ..ServerConfig::default()
+        };
+        let requests = "GET /1 HTTP/1.1\r\n\r\nGET /2 HTTP/1.1\r\n\r\nGET /3 HTTP/1.1\r\n\r\n";
+
+        let response = exchange(config, requests.as_bytes()).await;
+
+        let answers: Vec<&str> = response.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(answers.len(), 2, "{}", response);
+        assert!(answers[0].contains("Keep-Alive: timeout=5, max=1"));
+        assert!(answers[1].contains("Connection: close"));
+        assert!(answers[1].ends_with("/2 "));
+    }
+
+    const CHUNKED: &str = "POST /t HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    #[test]
+    fn chunked_bodies_are_decoded() {
+        let buf = format!(
+            "{}4;ext=1\r\nWiki\r\n6\r\npedia \r\n0\r\nX-Trailer: 1\r\n\r\n",
+            CHUNKED
+        );
+        let next = "GET /next HTTP/1.1\r\n\r\n";
+
+        let (req, consumed) = parse(&format!("{}{}", buf, next)).unwrap();
+        assert_eq!(req.body, "Wikipedia ");
+        assert_eq!(consumed, buf.len());
+
+        for cut in [buf.len() - 2, buf.len() - 20, CHUNKED.len() + 3] {
+            assert!(
+                matches!(parse(&buf[..cut]), Err(HttpParseError::Incomplete)),
+                "{}",
+                cut
+            );
+        }
+    }
+
+    #[test]
+    fn parser_resumes_where_the_previous_read_left_off() {
+        let chunked = format!(
+            "{}4\r\nWiki\r\n6;x=y\r\npedia \r\n0\r\nX-Trailer: 1\r\n\r\n",
+            CHUNKED
+        );
+        let sized = "POST /s HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+
+        for request in [chunked.as_str(), sized] {
+            let buf = format!("{}GET /next HTTP/1.1\r\n\r\n", request);
+            let mut parser = RequestParser::default();
+
+            // One byte per read, as a slow client might send it.
+            for len in 0..request.len() {
+                let result = parser.parse(&buf.as_bytes()[..len], &RequestLimits::default());
+                assert!(matches!(result, Err(HttpParseError::Incomplete)), "{}", len);
+            }
+            let (req, consumed) = parser
+                .parse(buf.as_bytes(), &RequestLimits::default())
+                .unwrap();
+            assert_eq!(consumed, request.len());
+            assert_eq!(req.body, parse(request).unwrap().0.body);
+
+            // The parser starts over for the next request.
+            let (req, _) = parser
+                .parse(&buf.as_bytes()[consumed..], &RequestLimits::default())
+                .unwrap();
+            assert_eq!(req.method.path(), "/next");
+        }
+    }
+
+    #[test]
+    fn malformed_chunks_are_rejected() {
+        for chunks in [
+            "zz\r\nabc\r\n0\r\n\r\n",
+            "3\r\nabcd\r\n0\r\n\r\n",
+            "\r\n\r\n",
+        ] {
+            let err = parse(&format!("{}{}", CHUNKED, chunks)).unwrap_err();
+            assert!(matches!(err, HttpParseError::InvalidChunk), "{:?}", chunks);
+            assert_eq!(err.status(), 400);
+        }
+    }
+
+    #[test]
+    fn oversized_chunked_bodies_are_rejected_before_they_arrive() {
+        let limits = RequestLimits {
+            max_body_bytes: 8,
+            ..RequestLimits::default()
+        };
+        let parse =
+            |chunks: &str| HttpRequest::parse(format!("{}{}", CHUNKED, chunks).as_bytes(), &limits);
+
+        assert!(parse("8\r\n12345678\r\n0\r\n\r\n").is_ok());
+        // The second chunk's size alone gives it away.
+        let err = parse("5\r\n12345\r\n4\r\n").unwrap_err();
+        assert!(matches!(err, HttpParseError::BodyTooLarge));
+        assert_eq!(err.status(), 413);
+        // As does a size line that never ends.
+        let long_line = "0".repeat(RequestLimits::default().max_header_bytes + 2);
+        assert!(matches!(
+            parse(&long_line),
+            Err(HttpParseError::HeadersTooLarge)
+        ));
+    }
+
+    #[tokio::test]
+    async fn malformed_chunked_request_is_answered_with_400() {
+        let request = format!("{}nothex\r\n", CHUNKED);
+
+        let response = exchange(ServerConfig::default(), request.as_bytes()).await;
+
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("Connection: close"));
+    }
+
+    #[test]
+    fn header_and_body_limits_are_enforced_early() {
+        let limits = RequestLimits {
+            max_header_bytes: 64,
+            max_headers: 2,
+            max_body_bytes: 10,
+        };
+        let parse = |buf: String| HttpRequest::parse(buf.as_bytes(), &limits);
+
+        let long = format!("GET /{} HTTP/1.1\r\n", "a".repeat(64));
+        assert!(matches!(parse(long), Err(HttpParseError::HeadersTooLarge)));
+
+        let many = "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n".to_string();
+        assert!(matches!(parse(many), Err(HttpParseError::TooManyHeaders)));
+
+        // Refused on the header alone, before any of the body is read.
+        let large = "POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n".to_string();
+        let err = parse(large).unwrap_err();
+        assert!(matches!(err, HttpParseError::BodyTooLarge));
+        assert_eq!(err.status(), 413);
+
+        let fits = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789".to_string();
+        assert!(parse(fits).is_ok());
+
+        assert_eq!(HttpParseError::HeadersTooLarge.status(), 431);
+        assert_eq!(HttpParseError::TooManyHeaders.status(), 431);
+    }
+
+    #[tokio::test]
+    async fn oversized_requests_are_answered_with_413_and_431() {
+        let config = || ServerConfig {
+            limits: RequestLimits {
+                max_header_bytes: 64,
+                max_headers: 64,
+                max_body_bytes: 10,
+            },
+            ..ServerConfig::default()
+        };
+
+        let request = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(64));
+        let response = exchange(config(), request.as_bytes()).await;
+        assert!(
+            response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+            "{}",
+            response
+        );
+
+        let request = "POST / HTTP/1.1\r\nContent-Length: 1000\r\n\r\n";
+        let response = exchange(config(), request.as_bytes()).await;
+        assert!(
+            response.starts_with("HTTP/1.1 413 Content Too Large\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_too_slow_to_finish_its_request_gets_408() {
+        let request = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nonly";
+
+        let response = exchange(ServerConfig::default(), request.as_bytes()).await;
+
+        assert!(
+            response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn query_strings_are_decoded_and_typed() {
+        let query = Query::parse("name=Ann+Lee&city=T%C3%A4ll&n=5&bad=%zz&flag");
+
+        assert_eq!(query.get("name"), Some("Ann Lee"));
+        assert_eq!(query.get("city"), Some("Täll"));
+        assert_eq!(query.get("flag"), Some(""));
+        assert_eq!(query.get("bad"), None);
+        assert_eq!(query.parse_param::<u32>("n"), Ok(Some(5)));
+        assert_eq!(query.parse_param::<u32>("missing"), Ok(None));
+        assert_eq!(
+            query.parse_param::<u32>("name"),
+            Err(InvalidParam("name".into()))
+        );
+    }
+
+    #[test]
+    fn path_patterns_capture_decoded_segments() {
+        let params =
+            PathParams::match_pattern("/users/{name}/history", "/users/Ann%20Lee/history").unwrap();
+        assert_eq!(params.get::<String>("name").as_deref(), Some("Ann Lee"));
+
+        assert!(PathParams::match_pattern("/users/{name}", "/users/").is_none());
+        assert!(PathParams::match_pattern("/users/{name}", "/users/a/b").is_none());
+        assert!(PathParams::match_pattern("/users/{name}/history", "/users/a").is_none());
+        assert!(PathParams::match_pattern("/peers", "/users").is_none());
+
+        let path = fill_pattern("/users/{name}/history", &[("name", "Ann Lee/2".into())]);
+        assert_eq!(path, "/users/Ann%20Lee%2F2/history");
+        assert_eq!(split_target("/blocks?limit=2").1.get("limit"), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn head_requests_get_the_headers_of_get_without_the_body() {
+        let requests = "HEAD /abc HTTP/1.1\r\n\r\nGET /abc HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+        let response = exchange(ServerConfig::default(), requests.as_bytes()).await;
+
+        let answers: Vec<&str> = response.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(answers.len(), 2, "{}", response);
+        assert!(answers[0].starts_with("200 OK\r\n"));
+        assert!(answers[0].contains("Content-Length: 5\r\n"));
+        assert!(answers[0].ends_with("\r\n\r\n"));
+        assert!(answers[1].ends_with("\r\n\r\n/abc "));
+    }
+
+    #[test]
+    fn every_status_sent_has_a_reason_phrase() {
+        for status in [
+            200, 201, 204, 304, 400, 404, 405, 408, 409, 413, 429, 431, 500, 501, 503, 505,
+        ] {
+            assert_ne!(reason_phrase(status), "Unknown", "{}", status);
+        }
+        assert!(is_bodiless(204) && is_bodiless(304) && !is_bodiless(200));
+    }
+
+    /// Answers with the path once `gate` lets it, telling `started` first.
+    struct Gated {
+        started: std::sync::Mutex<std::sync::mpsc::Sender<()>>,
+        gate: std::sync::Mutex<std::sync::mpsc::Receiver<()>>,
+    }
+
+    impl HttpHandler for Gated {
+        fn handle(&self, request: HttpRequest) -> HttpResult {
+            let _ = self.started.lock().unwrap().send(());
+            let _ = self.gate.lock().unwrap().recv();
+            HttpResult::text(200, "text/plain", request.method.path().to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn blocking_handlers_run_on_the_pool_and_overflow_gets_503() {
+        let (started_tx, started) = std::sync::mpsc::channel();
+        let (open, gate) = std::sync::mpsc::channel();
+        let handler = Gated {
+            started: std::sync::Mutex::new(started_tx),
+            gate: std::sync::Mutex::new(gate),
+        };
+        let blocking = Blocking::new(handler, 1, 1);
+        let get = |path: &str| {
+            blocking.handle(HttpRequest::new(
+                HttpMethod::GET(path.into()),
+                String::new(),
+            ))
+        };
+
+        let first = get("/first");
+        started.recv_timeout(Duration::from_secs(5)).unwrap();
+        let second = get("/second");
+
+        let busy = get("/third").await;
+        assert_eq!(busy.status, 503);
+        assert_eq!(busy.headers["Retry-After"], "1");
+
+        open.send(()).unwrap();
+        open.send(()).unwrap();
+        assert_eq!(first.await.body.into_string().await, "/first");
+        assert_eq!(second.await.body.into_string().await, "/second");
+        assert_eq!(blocking.metrics().snapshot().rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn idle_connections_close_when_the_server_starts_closing() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, remote_addr) = listener.accept().await.unwrap();
+        let (closing_tx, closing) = watch::channel(false);
+        let config = Arc::new(ServerConfig::default());
+        let server = tokio::spawn(handle_client(
+            stream,
+            remote_addr,
+            Arc::new(Echo),
+            config,
+            closing,
+        ));
+
+        client.write_all(b"GET /a HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut buf = [0u8; 1024];
+        let n = client.read(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).contains("Connection: keep-alive"));
+
+        closing_tx.send(true).unwrap();
+        timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
     }
 }
@@ -1,75 +1,329 @@
+use crate::sync::lock;
+use serde::Serialize;
 use std::{
-    sync::{Arc, Mutex, mpsc},
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
     thread,
 };
+use tracing::{debug, error};
 
+/// Fixed number of worker threads running jobs from a bounded queue.
+///
+/// When the queue is full new jobs are rejected rather than piling up, and
+/// a panicking job only fails itself: the worker catches the panic and
+/// carries on, and should a worker die anyway it is replaced.
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    shared: Arc<Shared>,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+struct Queue {
+    jobs: VecDeque<Job>,
+    closed: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    available: Condvar,
+    capacity: usize,
+    metrics: Arc<PoolMetrics>,
+}
+
+/// The queue was full, the job was not run.
+#[derive(Debug)]
+pub struct Rejected;
+
+/// Live counters of a pool, shareable with whoever reports on it.
+#[derive(Debug, Default)]
+pub struct PoolMetrics {
+    threads: usize,
+    queue_capacity: usize,
+    queued: AtomicUsize,
+    active: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    rejected: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PoolStats {
+    pub threads: usize,
+    pub queue_capacity: usize,
+    pub queued: usize,
+    pub active: usize,
+    pub completed: u64,
+    pub panicked: u64,
+    pub rejected: u64,
+}
+
+impl PoolMetrics {
+    pub fn snapshot(&self) -> PoolStats {
+        PoolStats {
+            threads: self.threads,
+            queue_capacity: self.queue_capacity,
+            queued: self.queued.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            panicked: self.panicked.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
 impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
+    pub fn new(size: usize, queue_capacity: usize) -> ThreadPool {
         assert!(size > 0);
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::with_capacity(queue_capacity),
+                closed: false,
+            }),
+            available: Condvar::new(),
+            capacity: queue_capacity,
+            metrics: Arc::new(PoolMetrics {
+                threads: size,
+                queue_capacity,
+                ..PoolMetrics::default()
+            }),
+        });
 
         let mut workers = Vec::with_capacity(size);
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, Arc::clone(&shared)));
         }
 
-        ThreadPool {
-            workers,
-            sender: Some(sender),
-        }
+        ThreadPool { workers, shared }
     }
 
-    pub fn execute<F>(&self, f: F)
+    /// Queues `f` to run on a worker, or rejects it if the queue is full.
+    pub fn execute<F>(&self, f: F) -> Result<(), Rejected>
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        let mut queue = lock(&self.shared.queue);
+
+        if queue.jobs.len() >= self.shared.capacity {
+            self.shared.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(Rejected);
+        }
+
+        queue.jobs.push_back(Box::new(f));
+        self.shared.metrics.queued.fetch_add(1, Ordering::Relaxed);
+        drop(queue);
+
+        self.shared.available.notify_one();
+        Ok(())
+    }
+
+    pub fn metrics(&self) -> Arc<PoolMetrics> {
+        Arc::clone(&self.shared.metrics)
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
+        // Workers finish the jobs still queued before they exit.
+        lock(&self.shared.queue).closed = true;
+        self.shared.available.notify_all();
 
         for worker in self.workers.drain(..) {
-            eprintln!("Shutting down worker {}", worker.id);
+            debug!(worker = worker.id, "shutting down worker");
 
-            worker.thread.join().unwrap();
+            let thread = lock(&worker.thread).take();
+            if let Some(thread) = thread {
+                let _ = thread.join();
+            }
         }
     }
 }
 
 struct Worker {
     id: usize,
-    thread: thread::JoinHandle<()>,
+    /// Current thread of the worker; replaced if it dies.
+    thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || {
+    fn new(id: usize, shared: Arc<Shared>) -> Worker {
+        let thread = Arc::new(Mutex::new(None));
+        spawn_thread(id, shared, Arc::clone(&thread));
+        Worker { id, thread }
+    }
+}
+
+fn spawn_thread(id: usize, shared: Arc<Shared>, slot: Arc<Mutex<Option<thread::JoinHandle<()>>>>) {
+    let thread_slot = Arc::clone(&slot);
+    let mut handle = lock(&slot);
+
+    *handle = Some(thread::spawn(move || {
+        let _respawn = Respawn {
+            id,
+            shared: Arc::clone(&shared),
+            slot: thread_slot,
+        };
+        run(id, &shared);
+    }));
+}
+
+fn run(id: usize, shared: &Shared) {
+    let metrics = &shared.metrics;
+
+    loop {
+        let job = {
+            let mut queue = lock(&shared.queue);
             loop {
-                let message = receiver.lock().unwrap().recv();
-
-                match message {
-                    Ok(job) => job(),
-                    Err(_) => {
-                        eprintln!("Worker {id} disconnected; shutting down.");
-                        break;
-                    }
+                if let Some(job) = queue.jobs.pop_front() {
+                    break job;
                 }
+                if queue.closed {
+                    debug!(worker = id, "queue closed, worker exiting");
+                    return;
+                }
+                queue = shared.available.wait(queue).unwrap();
             }
-        });
+        };
 
-        Worker { id, thread }
+        metrics.queued.fetch_sub(1, Ordering::Relaxed);
+        let active = ActiveJob::start(metrics);
+
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!(worker = id, "job panicked");
+            metrics.panicked.fetch_add(1, Ordering::Relaxed);
+        }
+
+        drop(active);
+        metrics.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a job as active until it is dropped, which also happens when the
+/// worker's thread dies running it.
+struct ActiveJob<'a>(&'a PoolMetrics);
+
+impl<'a> ActiveJob<'a> {
+    fn start(metrics: &'a PoolMetrics) -> Self {
+        metrics.active.fetch_add(1, Ordering::Relaxed);
+        ActiveJob(metrics)
+    }
+}
+
+impl Drop for ActiveJob<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Starts a replacement thread if the worker's thread dies by panicking.
+struct Respawn {
+    id: usize,
+    shared: Arc<Shared>,
+    slot: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+}
+
+impl Drop for Respawn {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            error!(worker = self.id, "worker died, starting a new one");
+            spawn_thread(self.id, Arc::clone(&self.shared), Arc::clone(&self.slot));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn panicking_jobs_only_fail_themselves() {
+        let pool = ThreadPool::new(1, 8);
+        let (tx, rx) = mpsc::channel();
+
+        pool.execute(|| panic!("job failed")).unwrap();
+        pool.execute(move || tx.send(()).unwrap()).unwrap();
+
+        rx.recv_timeout(WAIT).unwrap();
+        let metrics = pool.metrics();
+        drop(pool);
+        let stats = metrics.snapshot();
+        assert_eq!((stats.panicked, stats.completed, stats.active), (1, 2, 0));
+    }
+
+    /// Panic payload that panics again when dropped, which happens outside
+    /// the worker's `catch_unwind` and so kills its thread.
+    struct PanicOnDrop;
+
+    impl Drop for PanicOnDrop {
+        fn drop(&mut self) {
+            panic!("payload dropped");
+        }
+    }
+
+    #[test]
+    fn dead_worker_is_replaced() {
+        let pool = ThreadPool::new(1, 8);
+        let (tx, rx) = mpsc::channel();
+
+        pool.execute(|| panic::panic_any(PanicOnDrop)).unwrap();
+        for i in 0..3 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(i).unwrap()).unwrap();
+        }
+
+        let done: Vec<i32> = (0..3).map(|_| rx.recv_timeout(WAIT).unwrap()).collect();
+        assert_eq!(done, [0, 1, 2]);
+
+        let metrics = pool.metrics();
+        drop(pool);
+        let stats = metrics.snapshot();
+        assert_eq!((stats.active, stats.queued, stats.completed), (0, 0, 3));
+    }
+
+    #[test]
+    fn full_queue_rejects_jobs() {
+        let pool = ThreadPool::new(1, 1);
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        })
+        .unwrap();
+        started.recv_timeout(WAIT).unwrap();
+
+        pool.execute(|| {}).unwrap();
+        assert!(pool.execute(|| {}).is_err());
+        let stats = pool.metrics().snapshot();
+        assert_eq!((stats.active, stats.queued, stats.rejected), (1, 1, 1));
+
+        release.send(()).unwrap();
+        drop(pool);
+    }
+
+    #[test]
+    fn dropping_the_pool_runs_queued_jobs_first() {
+        let pool = ThreadPool::new(2, 16);
+        let (tx, rx) = mpsc::channel();
+
+        for i in 0..10 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(i).unwrap()).unwrap();
+        }
+        drop(pool);
+
+        let mut done: Vec<i32> = rx.try_iter().collect();
+        done.sort();
+        assert_eq!(done, (0..10).collect::<Vec<_>>());
     }
 }
@@ -1,7 +1,10 @@
-use lazy_static::lazy_static;
+use crate::events::{Event, Events};
+use crate::node::transactions::{self, ParsedTx};
+use crate::sync::lock;
 use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
 use std::sync::Mutex;
+use tracing::{debug, info};
 
 #[derive(Debug, Clone)]
 pub struct Block {
@@ -15,8 +18,13 @@ pub struct Block {
 pub const MINING_COMPLEXITY: usize = 5;
 
 impl Block {
-    pub fn new(prev_hash: String, transactions: Vec<Transaction>, timestamp: u64) -> Self {
-        let (nonce, hash) = Self::mine(&prev_hash, &transactions, timestamp);
+    pub fn new(
+        prev_hash: String,
+        transactions: Vec<Transaction>,
+        timestamp: u64,
+        difficulty: usize,
+    ) -> Self {
+        let (nonce, hash) = Self::mine(&prev_hash, &transactions, timestamp, difficulty);
 
         Self {
             hash,
@@ -27,31 +35,39 @@ impl Block {
         }
     }
 
-    pub fn mine(prev_hash: &str, transactions: &[Transaction], timestamp: u64) -> (u64, String) {
+    pub fn mine(
+        prev_hash: &str,
+        transactions: &[Transaction],
+        timestamp: u64,
+        difficulty: usize,
+    ) -> (u64, String) {
         let mut nonce = 0;
         let tx_hashes: String = transactions.iter().map(|t| t.hash.as_str()).collect();
 
         loop {
             let hash = compute_hash(&format!("{}{}{}{}", prev_hash, tx_hashes, timestamp, nonce));
 
-            if Self::has_valid_prefix(&hash) {
-                return (nonce, hash)
+            if Self::has_valid_prefix(&hash, difficulty) {
+                return (nonce, hash);
             }
 
             nonce += 1;
         }
     }
 
-    pub fn has_valid_prefix(hash: &str) -> bool {
-        hash.starts_with(&"0".repeat(MINING_COMPLEXITY))
+    pub fn has_valid_prefix(hash: &str, difficulty: usize) -> bool {
+        hash.starts_with(&"0".repeat(difficulty))
     }
 
     /// Check that the hash inside the block actually matches the content
-    pub fn is_valid(&self) -> bool {
+    pub fn is_valid(&self, difficulty: usize) -> bool {
         let tx_hashes: String = self.transactions.iter().map(|t| t.hash.as_str()).collect();
-        let expected = compute_hash(&format!("{}{}{}{}", self.prev_hash, tx_hashes, self.timestamp, self.nonce));
+        let expected = compute_hash(&format!(
+            "{}{}{}{}",
+            self.prev_hash, tx_hashes, self.timestamp, self.nonce
+        ));
 
-        self.hash == expected && Self::has_valid_prefix(&self.hash)
+        self.hash == expected && Self::has_valid_prefix(&self.hash, difficulty)
     }
 }
 
@@ -73,11 +89,6 @@ impl Transaction {
             timestamp,
         }
     }
-
-    pub fn from_data(data: String) -> Self {
-        let timestamp = now();
-        Self::new(data, timestamp)
-    }
 }
 
 #[derive(Debug, Clone)]
@@ -89,8 +100,33 @@ struct StoredBlock {
 #[derive(Debug, Default)]
 struct LedgerState {
     blocks_by_hash: HashMap<String, StoredBlock>,
+    /// Hashes of the known children of every block with any.
+    children: HashMap<String, Vec<String>>,
     main_chain: Vec<Block>,
     best_tip: String,
+    /// Main chain position of every transaction on the main chain.
+    tx_index: HashMap<String, usize>,
+    /// Main chain transactions naming each user, as block and transaction
+    /// positions, oldest first.
+    address_index: HashMap<String, Vec<(usize, usize)>>,
+    /// Balances right after every block whose height is a multiple of
+    /// `SNAPSHOT_INTERVAL`, forks included. They only depend on the
+    /// block's ancestors, so a reorg never invalidates them.
+    snapshots: HashMap<String, HashMap<String, i64>>,
+    reorgs: ReorgStats,
+}
+
+/// Blocks between two balance snapshots on a branch, and so the most a
+/// historical balance query has to replay.
+const SNAPSHOT_INTERVAL: usize = 100;
+
+/// Main chain switches to a competing branch seen so far.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReorgStats {
+    pub count: u64,
+    /// Main chain blocks dropped by the last and the deepest reorg.
+    pub last_depth: usize,
+    pub max_depth: usize,
 }
 
 #[derive(Debug, Default)]
@@ -98,6 +134,45 @@ struct TxPool {
     known_by_hash: HashMap<String, Transaction>,
 }
 
+/// A block of the block tree, on the main chain or not.
+#[derive(Debug, Clone)]
+pub struct BlockRef {
+    pub hash: String,
+    pub height: usize,
+    pub on_main_chain: bool,
+}
+
+/// A main chain transaction and where it is.
+#[derive(Debug, Clone)]
+pub struct ChainTx {
+    pub block_hash: String,
+    pub height: usize,
+    /// Position of the transaction within its block.
+    pub index: usize,
+    pub transaction: Transaction,
+}
+
+/// A transaction the ledger knows about and where it is.
+#[derive(Debug, Clone)]
+pub struct TxLookup {
+    pub transaction: Transaction,
+    /// Hash and height of the main chain block holding it, unless it is
+    /// still pending.
+    pub block: Option<(String, usize)>,
+    /// Main chain length at the time of the lookup.
+    pub chain_len: usize,
+}
+
+impl TxLookup {
+    /// Blocks on top of and including the one holding the transaction.
+    pub fn confirmations(&self) -> usize {
+        match self.block {
+            Some((_, height)) => self.chain_len - height + 1,
+            None => 0,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum AddBlockResult {
     Added,
@@ -106,29 +181,18 @@ pub enum AddBlockResult {
     Invalid,
 }
 
-lazy_static! {
-    static ref LEDGER: Mutex<LedgerState> = Mutex::new(LedgerState::default());
-    static ref TX_POOL: Mutex<TxPool> = Mutex::new(TxPool::default());
-    static ref ORPHAN_BLOCKS: Mutex<HashMap<String, Vec<Block>>> = Mutex::new(HashMap::new());
-}
-
-pub fn init_genesis_block() {
-    let timestamp = 0;
-
-    let tx = Transaction::new("Alice=100".to_string(), timestamp);
-    let block = Block::new(String::new(), vec![tx], timestamp);
-
-    let mut ledger = LEDGER.lock().unwrap();
-    let height = 1;
-    ledger.blocks_by_hash.insert(
-        block.hash.clone(),
-        StoredBlock {
-            block: block.clone(),
-            height,
-        },
-    );
-    ledger.best_tip = block.hash.clone();
-    ledger.main_chain = vec![block];
+/// Block tree, main chain, mempool and orphan pool of a single node.
+///
+/// Every height the ledger takes or returns counts the genesis block as 1,
+/// so the main chain block at height `h` is `main_chain[h - 1]` and the tip's
+/// height is the chain length.
+#[derive(Debug)]
+pub struct Ledger {
+    difficulty: usize,
+    state: Mutex<LedgerState>,
+    tx_pool: Mutex<TxPool>,
+    orphan_blocks: Mutex<HashMap<String, Vec<Block>>>,
+    events: Events,
 }
 
 pub fn compute_hash(data: &str) -> String {
@@ -138,223 +202,685 @@ pub fn compute_hash(data: &str) -> String {
     hex::encode(result)
 }
 
-pub fn now() -> u64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-}
+impl Ledger {
+    /// Creates a ledger holding only the genesis block. Every node in a
+    /// network has to use the same `difficulty`, otherwise they end up with
+    /// different genesis blocks.
+    pub fn new(difficulty: usize) -> Self {
+        let timestamp = 0;
+
+        let tx = Transaction::new("Alice=100".to_string(), timestamp);
+        let block = Block::new(String::new(), vec![tx], timestamp, difficulty);
+
+        let mut state = LedgerState::default();
+        let height = 1;
+        state.blocks_by_hash.insert(
+            block.hash.clone(),
+            StoredBlock {
+                block: block.clone(),
+                height,
+            },
+        );
+        state.best_tip = block.hash;
+        update_main_chain(&mut state);
+
+        Ledger {
+            difficulty,
+            state: Mutex::new(state),
+            tx_pool: Mutex::new(TxPool::default()),
+            orphan_blocks: Mutex::new(HashMap::new()),
+            events: Events::new(),
+        }
+    }
+
+    /// Publishes changes to the main chain and mempool to `events`.
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
 
-pub fn add_block(block: &Block) -> AddBlockResult {
-    if !block.is_valid() {
-        return AddBlockResult::Invalid;
+    pub fn difficulty(&self) -> usize {
+        self.difficulty
     }
 
-    remember_block_transactions(block);
+    pub fn add_block(&self, block: &Block) -> AddBlockResult {
+        if !block.is_valid(self.difficulty) {
+            return AddBlockResult::Invalid;
+        }
+
+        self.remember_block_transactions(block);
 
-    {
-        let ledger = LEDGER.lock().unwrap();
-        if ledger.blocks_by_hash.contains_key(&block.hash) {
-            return AddBlockResult::Duplicate;
+        {
+            let state = lock(&self.state);
+            if state.blocks_by_hash.contains_key(&block.hash) {
+                return AddBlockResult::Duplicate;
+            }
+        }
+
+        if !block.prev_hash.is_empty() {
+            let parent_known = {
+                let state = lock(&self.state);
+                state.blocks_by_hash.contains_key(&block.prev_hash)
+            };
+
+            if !parent_known {
+                let mut orphans = lock(&self.orphan_blocks);
+                let entry = orphans.entry(block.prev_hash.clone()).or_default();
+                if entry.iter().any(|b| b.hash == block.hash) {
+                    return AddBlockResult::Duplicate;
+                }
+                entry.push(block.clone());
+                debug!(block = %block.hash, parent = %block.prev_hash, "stored orphan block");
+                return AddBlockResult::StoredAsOrphan;
+            }
         }
+
+        self.insert_block_and_update_best_chain(block.clone());
+        self.process_orphans(block.hash.clone());
+
+        info!(block = %block.hash, txs = block.transactions.len(), "added block");
+        AddBlockResult::Added
     }
 
-    if !block.prev_hash.is_empty() {
-        let parent_known = {
-            let ledger = LEDGER.lock().unwrap();
-            ledger.blocks_by_hash.contains_key(&block.prev_hash)
+    fn insert_block_and_update_best_chain(&self, block: Block) {
+        let mut state = lock(&self.state);
+
+        if state.blocks_by_hash.contains_key(&block.hash) {
+            return;
+        }
+
+        let height = if block.prev_hash.is_empty() {
+            1
+        } else {
+            match state.blocks_by_hash.get(&block.prev_hash) {
+                Some(parent) => parent.height + 1,
+                None => return,
+            }
         };
 
-        if !parent_known {
-            let mut orphans = ORPHAN_BLOCKS.lock().unwrap();
-            let entry = orphans.entry(block.prev_hash.clone()).or_default();
-            if entry.iter().any(|b| b.hash == block.hash) {
-                return AddBlockResult::Duplicate;
+        state.blocks_by_hash.insert(
+            block.hash.clone(),
+            StoredBlock {
+                block: block.clone(),
+                height,
+            },
+        );
+        state
+            .children
+            .entry(block.prev_hash.clone())
+            .or_default()
+            .push(block.hash.clone());
+
+        if height % SNAPSHOT_INTERVAL == 0 {
+            balances_after(&mut state, &block.hash);
+        }
+
+        let current_best_height = state
+            .blocks_by_hash
+            .get(&state.best_tip)
+            .map(|b| b.height)
+            .unwrap_or(0);
+
+        if height > current_best_height {
+            let old_tip = std::mem::replace(&mut state.best_tip, block.hash.clone());
+            let fork_height = update_main_chain(&mut state);
+
+            if fork_height < current_best_height {
+                record_reorg(&mut state, &old_tip, current_best_height, fork_height);
             }
-            entry.push(block.clone());
-            println!(
-                "[LEDGER] Stored orphan block {} waiting for {}",
-                block.hash, block.prev_hash
-            );
-            return AddBlockResult::StoredAsOrphan;
+            self.publish_main_chain_changes(&state, &old_tip, current_best_height, fork_height);
         }
     }
 
-    insert_block_and_update_best_chain(block.clone());
-    process_orphans(block.hash.clone());
+    /// Publishes the switch away from `old_tip` if blocks above
+    /// `fork_height` were dropped, then every block and transaction that
+    /// joined the main chain above it.
+    fn publish_main_chain_changes(
+        &self,
+        state: &LedgerState,
+        old_tip: &str,
+        old_height: usize,
+        fork_height: usize,
+    ) {
+        if fork_height < old_height {
+            self.events.publish(Event::Reorg {
+                old_tip: old_tip.to_string(),
+                new_tip: state.best_tip.clone(),
+                depth: old_height - fork_height,
+            });
+        }
 
-    println!("[LEDGER] Added block: {}", block.hash);
-    AddBlockResult::Added
-}
+        for (position, block) in state.main_chain.iter().enumerate().skip(fork_height) {
+            self.events.publish(Event::Block {
+                hash: block.hash.clone(),
+                height: position + 1,
+            });
+
+            for tx in &block.transactions {
+                // Transactions already confirmed further down stay there.
+                if state.tx_index.get(&tx.hash) == Some(&position) {
+                    self.events.publish(Event::TxConfirmed {
+                        hash: tx.hash.clone(),
+                        block_hash: block.hash.clone(),
+                        height: position + 1,
+                    });
+                }
+            }
+        }
+    }
+
+    fn process_orphans(&self, starting_parent_hash: String) {
+        let mut queue = vec![starting_parent_hash];
+        let mut seen_parents = HashSet::new();
+
+        while let Some(parent_hash) = queue.pop() {
+            if !seen_parents.insert(parent_hash.clone()) {
+                continue;
+            }
 
-fn insert_block_and_update_best_chain(block: Block) {
-    let mut ledger = LEDGER.lock().unwrap();
+            let children = {
+                let mut orphans = lock(&self.orphan_blocks);
+                orphans.remove(&parent_hash).unwrap_or_default()
+            };
 
-    if ledger.blocks_by_hash.contains_key(&block.hash) {
-        return;
+            for child in children {
+                let child_hash = child.hash.clone();
+                self.insert_block_and_update_best_chain(child);
+                queue.push(child_hash);
+            }
+        }
     }
 
-    let height = if block.prev_hash.is_empty() {
-        1
-    } else {
-        match ledger.blocks_by_hash.get(&block.prev_hash) {
-            Some(parent) => parent.height + 1,
-            None => return,
+    fn remember_block_transactions(&self, block: &Block) {
+        let mut pool = lock(&self.tx_pool);
+        for tx in &block.transactions {
+            pool.known_by_hash
+                .entry(tx.hash.clone())
+                .or_insert_with(|| tx.clone());
         }
-    };
-
-    ledger.blocks_by_hash.insert(
-        block.hash.clone(),
-        StoredBlock {
-            block: block.clone(),
-            height,
-        },
-    );
+    }
 
-    let current_best_height = ledger
-        .blocks_by_hash
-        .get(&ledger.best_tip)
-        .map(|b| b.height)
-        .unwrap_or(0);
+    pub fn add_transaction(&self, transaction: &Transaction) -> bool {
+        let mut pool = lock(&self.tx_pool);
+        if pool.known_by_hash.contains_key(&transaction.hash) {
+            return false;
+        }
 
-    if height > current_best_height {
-        ledger.best_tip = block.hash.clone();
-        rebuild_main_chain(&mut ledger);
+        pool.known_by_hash
+            .insert(transaction.hash.clone(), transaction.clone());
+
+        debug!(tx = %transaction.hash, "added transaction");
+        self.events.publish(Event::TxAccepted {
+            hash: transaction.hash.clone(),
+            data: transaction.data.clone(),
+        });
+        true
     }
-}
 
-fn rebuild_main_chain(ledger: &mut LedgerState) {
-    let mut chain = Vec::new();
-    let mut cursor = ledger.best_tip.clone();
+    pub fn get_pending_transactions(&self) -> Vec<Transaction> {
+        let state = lock(&self.state);
+        let pool = lock(&self.tx_pool);
 
-    while !cursor.is_empty() {
-        let Some(stored) = ledger.blocks_by_hash.get(&cursor) else {
-            break;
-        };
+        let mut pending: Vec<Transaction> = pool
+            .known_by_hash
+            .values()
+            .filter(|tx| !state.tx_index.contains_key(&tx.hash))
+            .cloned()
+            .collect();
 
-        chain.push(stored.block.clone());
-        cursor = stored.block.prev_hash.clone();
+        pending.sort_by(|a, b| (a.timestamp, &a.hash).cmp(&(b.timestamp, &b.hash)));
+        pending
     }
 
-    chain.reverse();
-    ledger.main_chain = chain;
-}
+    /// Looks `hash` up on the main chain, then among pending transactions.
+    pub fn find_transaction(&self, hash: &str) -> Option<TxLookup> {
+        let state = lock(&self.state);
+        let chain_len = state.main_chain.len();
 
-fn process_orphans(starting_parent_hash: String) {
-    let mut queue = vec![starting_parent_hash];
-    let mut seen_parents = HashSet::new();
+        if let Some(&position) = state.tx_index.get(hash) {
+            let block = &state.main_chain[position];
+            let transaction = block.transactions.iter().find(|tx| tx.hash == hash)?;
 
-    while let Some(parent_hash) = queue.pop() {
-        if !seen_parents.insert(parent_hash.clone()) {
-            continue;
+            return Some(TxLookup {
+                transaction: transaction.clone(),
+                block: Some((block.hash.clone(), position + 1)),
+                chain_len,
+            });
         }
 
-        let children = {
-            let mut orphans = ORPHAN_BLOCKS.lock().unwrap();
-            orphans.remove(&parent_hash).unwrap_or_default()
+        let pool = lock(&self.tx_pool);
+        pool.known_by_hash.get(hash).map(|tx| TxLookup {
+            transaction: tx.clone(),
+            block: None,
+            chain_len,
+        })
+    }
+
+    /// Main chain transactions naming user `name`, oldest first.
+    pub fn get_address_transactions(&self, name: &str) -> Vec<ChainTx> {
+        let state = lock(&self.state);
+        let Some(positions) = state.address_index.get(name) else {
+            return Vec::new();
         };
 
-        for child in children {
-            let child_hash = child.hash.clone();
-            insert_block_and_update_best_chain(child);
-            queue.push(child_hash);
+        positions
+            .iter()
+            .map(|&(block, tx)| {
+                let stored = &state.main_chain[block];
+                ChainTx {
+                    block_hash: stored.hash.clone(),
+                    height: block + 1,
+                    index: tx,
+                    transaction: stored.transactions[tx].clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Whether any main chain transaction names user `name`.
+    pub fn has_address(&self, name: &str) -> bool {
+        lock(&self.state).address_index.contains_key(name)
+    }
+
+    pub fn get_transactions_for_mining(&self, limit: usize) -> Vec<Transaction> {
+        let mut pending = self.get_pending_transactions();
+        pending.truncate(limit);
+        pending
+    }
+
+    pub fn pending_txs_len(&self) -> usize {
+        self.get_pending_transactions().len()
+    }
+
+    pub fn last_block_hash(&self) -> String {
+        let state = lock(&self.state);
+        state.best_tip.clone()
+    }
+
+    pub fn get_block(&self, hash: &str) -> Option<Block> {
+        let state = lock(&self.state);
+        state.blocks_by_hash.get(hash).map(|b| b.block.clone())
+    }
+
+    /// Main chain block at `height`, counting the genesis block as 1.
+    pub fn get_block_at_height(&self, height: usize) -> Option<Block> {
+        let state = lock(&self.state);
+        state.main_chain.get(height.checked_sub(1)?).cloned()
+    }
+
+    /// Up to `limit` main chain blocks from `height` on, with their heights.
+    pub fn get_blocks_range(&self, height: usize, limit: usize) -> Vec<(usize, Block)> {
+        let state = lock(&self.state);
+
+        state
+            .main_chain
+            .iter()
+            .enumerate()
+            .skip(height.saturating_sub(1))
+            .take(limit)
+            .map(|(i, b)| (i + 1, b.clone()))
+            .collect()
+    }
+
+    /// Blocks nothing has been built on yet, highest first. The first one
+    /// is the main chain tip, the others end forks.
+    pub fn chain_tips(&self) -> Vec<BlockRef> {
+        let state = lock(&self.state);
+
+        let mut tips: Vec<BlockRef> = state
+            .blocks_by_hash
+            .keys()
+            .filter(|hash| !state.children.contains_key(*hash))
+            .filter_map(|hash| block_ref(&state, hash))
+            .collect();
+
+        tips.sort_by(|a, b| {
+            (b.on_main_chain, b.height, &a.hash).cmp(&(a.on_main_chain, a.height, &b.hash))
+        });
+        tips
+    }
+
+    /// Known blocks built directly on `hash`, or `None` if the block itself
+    /// is unknown.
+    pub fn get_children(&self, hash: &str) -> Option<Vec<BlockRef>> {
+        let state = lock(&self.state);
+        if !state.blocks_by_hash.contains_key(hash) {
+            return None;
         }
+
+        let children = state
+            .children
+            .get(hash)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        Some(
+            children
+                .iter()
+                .filter_map(|child| block_ref(&state, child))
+                .collect(),
+        )
     }
-}
 
-fn confirmed_tx_hashes(ledger: &LedgerState) -> HashSet<String> {
-    ledger
-        .main_chain
-        .iter()
-        .flat_map(|block| block.transactions.iter().map(|tx| tx.hash.clone()))
-        .collect()
-}
+    /// Balances right after block `hash`, which may be on a fork, as
+    /// folded from the genesis block up to it. `None` if it is unknown.
+    pub fn balances_at(&self, hash: &str) -> Option<(BlockRef, HashMap<String, i64>)> {
+        let mut state = lock(&self.state);
+        let block = block_ref(&state, hash)?;
+        let balances = balances_after(&mut state, hash);
 
-fn remember_block_transactions(block: &Block) {
-    let mut pool = TX_POOL.lock().unwrap();
-    for tx in &block.transactions {
-        pool.known_by_hash
-            .entry(tx.hash.clone())
-            .or_insert_with(|| tx.clone());
+        Some((block, balances))
+    }
+
+    pub fn with_blocks<R>(&self, f: impl FnOnce(&[Block]) -> R) -> R {
+        let state = lock(&self.state);
+        f(&state.main_chain)
     }
-}
 
-pub fn add_transaction(transaction: &Transaction) -> bool {
-    let mut pool = TX_POOL.lock().unwrap();
-    if pool.known_by_hash.contains_key(&transaction.hash) {
-        return false;
+    pub fn reorg_stats(&self) -> ReorgStats {
+        lock(&self.state).reorgs
     }
 
-    pool.known_by_hash
-        .insert(transaction.hash.clone(), transaction.clone());
+    /// Blocks waiting for a parent that has not arrived yet.
+    pub fn orphan_count(&self) -> usize {
+        let orphans = lock(&self.orphan_blocks);
+        orphans.values().map(Vec::len).sum()
+    }
 
-    println!("[LEDGER] Added transaction: {}", transaction.hash);
-    true
-}
+    pub fn chain_len(&self) -> usize {
+        let state = lock(&self.state);
+        state.main_chain.len()
+    }
+
+    pub fn get_all_block_hashes(&self) -> Vec<String> {
+        let state = lock(&self.state);
+        state.main_chain.iter().map(|b| b.hash.clone()).collect()
+    }
+
+    /// Height of `hash`, if it is on the main chain.
+    pub fn main_chain_height(&self, hash: &str) -> Option<usize> {
+        let state = lock(&self.state);
+        block_ref(&state, hash)
+            .filter(|block| block.on_main_chain)
+            .map(|block| block.height)
+    }
 
-pub fn get_pending_transactions() -> Vec<Transaction> {
-    let ledger = LEDGER.lock().unwrap();
-    let confirmed = confirmed_tx_hashes(&ledger);
-    drop(ledger);
-
-    let pool = TX_POOL.lock().unwrap();
-    let mut pending: Vec<Transaction> = pool
-        .known_by_hash
-        .values()
-        .filter(|tx| !confirmed.contains(&tx.hash))
-        .cloned()
-        .collect();
-
-    pending.sort_by_key(|tx| tx.timestamp);
-    pending
+    /// Hashes of up to `limit` main chain blocks, starting at `height`.
+    pub fn get_block_hashes_range(&self, height: usize, limit: usize) -> Vec<String> {
+        let state = lock(&self.state);
+
+        state
+            .main_chain
+            .iter()
+            .skip(height.saturating_sub(1))
+            .take(limit)
+            .map(|b| b.hash.clone())
+            .collect()
+    }
 }
 
-pub fn get_transactions_for_mining(limit: usize) -> Vec<Transaction> {
-    let mut pending = get_pending_transactions();
-    pending.truncate(limit);
-    pending
+/// Moves the main chain over to `best_tip`. Only the blocks above the last
+/// one the old and new chains share are detached and attached, and the
+/// transaction indexes follow them. Returns the height of that shared
+/// block.
+fn update_main_chain(state: &mut LedgerState) -> usize {
+    let mut attached = Vec::new();
+    let mut cursor = state.best_tip.clone();
+    let tip_height = state.blocks_by_hash.get(&cursor).map_or(0, |b| b.height);
+
+    while let Some(stored) = state.blocks_by_hash.get(&cursor) {
+        if state
+            .main_chain
+            .get(stored.height - 1)
+            .is_some_and(|b| b.hash == cursor)
+        {
+            break;
+        }
+        attached.push(stored.block.clone());
+        cursor = stored.block.prev_hash.clone();
+    }
+
+    let fork_height = tip_height - attached.len();
+    unindex_transactions(state, fork_height);
+    state.main_chain.truncate(fork_height);
+
+    for block in attached.into_iter().rev() {
+        index_transactions(state, state.main_chain.len(), &block);
+        state.main_chain.push(block);
+    }
+
+    fork_height
 }
 
-pub fn pending_txs_len() -> usize {
-    get_pending_transactions().len()
+fn block_ref(state: &LedgerState, hash: &str) -> Option<BlockRef> {
+    let stored = state.blocks_by_hash.get(hash)?;
+    let on_main_chain = state
+        .main_chain
+        .get(stored.height - 1)
+        .is_some_and(|b| b.hash == hash);
+
+    Some(BlockRef {
+        hash: hash.to_string(),
+        height: stored.height,
+        on_main_chain,
+    })
 }
 
-pub fn last_block_hash() -> String {
-    let ledger = LEDGER.lock().unwrap();
-    ledger.best_tip.clone()
+/// Replays the blocks from the closest snapshot below `hash` up to it,
+/// snapshotting on the way where one is due.
+fn balances_after(state: &mut LedgerState, hash: &str) -> HashMap<String, i64> {
+    let mut replay = Vec::new();
+    let mut balances = HashMap::new();
+    let mut cursor = hash;
+
+    while let Some(stored) = state.blocks_by_hash.get(cursor) {
+        if let Some(snapshot) = state.snapshots.get(cursor) {
+            balances = snapshot.clone();
+            break;
+        }
+        replay.push(stored);
+        cursor = &stored.block.prev_hash;
+    }
+
+    let mut snapshots = Vec::new();
+    for stored in replay.into_iter().rev() {
+        transactions::apply_block(&mut balances, &stored.block);
+        if stored.height % SNAPSHOT_INTERVAL == 0 {
+            snapshots.push((stored.block.hash.clone(), balances.clone()));
+        }
+    }
+
+    state.snapshots.extend(snapshots);
+    balances
 }
 
-pub fn get_block(hash: &str) -> Option<Block> {
-    let ledger = LEDGER.lock().unwrap();
-    ledger.blocks_by_hash.get(hash).map(|b| b.block.clone())
+/// Users a transaction is indexed under.
+fn tx_addresses(tx: &Transaction) -> Vec<String> {
+    match transactions::parse_transaction(&tx.data) {
+        Some(ParsedTx::CreateUser { name, .. }) => vec![name],
+        Some(ParsedTx::Transfer { from, to, .. }) if from == to => vec![from],
+        Some(ParsedTx::Transfer { from, to, .. }) => vec![from, to],
+        None => Vec::new(),
+    }
 }
 
-pub fn with_blocks<R>(f: impl FnOnce(&[Block]) -> R) -> R {
-    let ledger = LEDGER.lock().unwrap();
-    f(&ledger.main_chain)
+/// Indexes the transactions of `block`, about to join the main chain at
+/// `position`.
+fn index_transactions(state: &mut LedgerState, position: usize, block: &Block) {
+    for (i, tx) in block.transactions.iter().enumerate() {
+        // A transaction mined twice counts from its first block.
+        if state.tx_index.contains_key(&tx.hash) {
+            continue;
+        }
+        state.tx_index.insert(tx.hash.clone(), position);
+
+        for name in tx_addresses(tx) {
+            state
+                .address_index
+                .entry(name)
+                .or_default()
+                .push((position, i));
+        }
+    }
 }
 
-pub fn chain_len() -> usize {
-    let ledger = LEDGER.lock().unwrap();
-    ledger.main_chain.len()
+/// Drops the index entries of the main chain blocks from `position` on,
+/// which are about to be detached.
+fn unindex_transactions(state: &mut LedgerState, position: usize) {
+    for block in state.main_chain.iter().skip(position) {
+        for tx in &block.transactions {
+            if state.tx_index.get(&tx.hash).is_some_and(|&p| p >= position) {
+                state.tx_index.remove(&tx.hash);
+            }
+
+            for name in tx_addresses(tx) {
+                let Some(positions) = state.address_index.get_mut(&name) else {
+                    continue;
+                };
+                // Positions are ascending, so the detached ones are last.
+                let kept = positions.partition_point(|&(p, _)| p < position);
+                positions.truncate(kept);
+                if positions.is_empty() {
+                    state.address_index.remove(&name);
+                }
+            }
+        }
+    }
 }
 
-pub fn get_all_block_hashes() -> Vec<String> {
-    let ledger = LEDGER.lock().unwrap();
-    ledger.main_chain.iter().map(|b| b.hash.clone()).collect()
+/// Counts the switch from the branch ending in `old_tip`, at `old_height`,
+/// to the new main chain, which shares its blocks up to `fork_height`.
+fn record_reorg(state: &mut LedgerState, old_tip: &str, old_height: usize, fork_height: usize) {
+    let depth = old_height - fork_height;
+
+    let reorgs = &mut state.reorgs;
+    reorgs.count += 1;
+    reorgs.last_depth = depth;
+    reorgs.max_depth = reorgs.max_depth.max(depth);
+
+    info!(
+        depth,
+        old_height,
+        old_tip,
+        new_tip = %state.best_tip,
+        "reorganised main chain"
+    );
 }
 
-pub fn get_block_hashes_after(start_hash: &str) -> Vec<String> {
-    let ledger = LEDGER.lock().unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFFICULTY: usize = 1;
+
+    fn mine(ledger: &Ledger, prev_hash: &str, data: &str, timestamp: u64) -> Block {
+        let tx = Transaction::new(data.to_string(), timestamp);
+        let block = Block::new(prev_hash.to_string(), vec![tx], timestamp, DIFFICULTY);
+        assert!(matches!(ledger.add_block(&block), AddBlockResult::Added));
+        block
+    }
 
-    if let Some(pos) = ledger.main_chain.iter().position(|b| b.hash == start_hash) {
+    fn heights(ledger: &Ledger, name: &str) -> Vec<usize> {
         ledger
-            .main_chain
+            .get_address_transactions(name)
             .iter()
-            .skip(pos + 1)
-            .map(|b| b.hash.clone())
+            .map(|tx| tx.height)
             .collect()
-    } else {
-        Vec::new()
+    }
+
+    #[test]
+    fn indexes_follow_the_main_chain_through_reorgs() {
+        let ledger = Ledger::new(DIFFICULTY);
+        let genesis = ledger.last_block_hash();
+
+        let a1 = mine(&ledger, &genesis, "Alice->Bob:5", 1);
+        let b1 = mine(&ledger, &genesis, "Alice->Carol:3", 2);
+        let to_bob = &a1.transactions[0].hash;
+        assert_eq!(heights(&ledger, "Bob"), [2]);
+        assert!(!ledger.has_address("Carol"));
+
+        // The longer branch takes over and drops Bob's transfer.
+        let b2 = mine(&ledger, &b1.hash, "Carol->Dave:1", 3);
+        assert_eq!(ledger.last_block_hash(), b2.hash);
+        assert!(!ledger.has_address("Bob"));
+        assert_eq!(ledger.find_transaction(to_bob).unwrap().block, None);
+        assert_eq!(heights(&ledger, "Alice"), [1, 2]);
+        assert_eq!(heights(&ledger, "Carol"), [2, 3]);
+
+        // And back, with Bob's transfer mined again on top.
+        let a2 = mine(&ledger, &a1.hash, "Alice->Erin:2", 4);
+        let a3 = Block::new(a2.hash.clone(), a1.transactions.clone(), 5, DIFFICULTY);
+        ledger.add_block(&a3);
+        assert_eq!(ledger.last_block_hash(), a3.hash);
+        assert!(!ledger.has_address("Carol"));
+        assert!(!ledger.has_address("Dave"));
+        assert_eq!(heights(&ledger, "Bob"), [2]);
+        assert_eq!(heights(&ledger, "Alice"), [1, 2, 3]);
+        assert_eq!(
+            ledger.find_transaction(to_bob).unwrap().block,
+            Some((a1.hash.clone(), 2))
+        );
+    }
+
+    #[test]
+    fn historical_balances_match_a_full_replay_around_snapshots() {
+        let ledger = Ledger::new(DIFFICULTY);
+        let mut tip = ledger.last_block_hash();
+
+        for i in 1..250u64 {
+            let data = match i % 3 {
+                0 => format!("User{}=10", i),
+                1 => "Alice->Bob:1".to_string(),
+                _ => "Bob->Alice:1".to_string(),
+            };
+            tip = mine(&ledger, &tip, &data, i).hash;
+        }
+        let chain = ledger.with_blocks(<[Block]>::to_vec);
+        assert_eq!(chain.len(), 250);
+
+        for height in [1, 99, 100, 101, 199, 200, 201, 250] {
+            let hash = &ledger.get_block_at_height(height).unwrap().hash;
+            let (at, balances) = ledger.balances_at(hash).unwrap();
+            assert_eq!((at.height, at.on_main_chain), (height, true));
+            assert_eq!(
+                balances,
+                transactions::compute_balances(&chain[..height]),
+                "{}",
+                height
+            );
+        }
+
+        // A losing branch forking just past the snapshot at 100.
+        let fork = mine(&ledger, &chain[100].hash, "Alice->Carol:7", 1000);
+        let (at, balances) = ledger.balances_at(&fork.hash).unwrap();
+        assert_eq!((at.height, at.on_main_chain), (102, false));
+        let mut branch = chain[..101].to_vec();
+        branch.push(fork);
+        assert_eq!(balances, transactions::compute_balances(&branch));
+        assert_eq!(balances.get("Carol"), Some(&7));
+
+        // A longer branch from 150 on takes over the snapshot at 200.
+        let mut tip = chain[149].hash.clone();
+        for i in 0..102u64 {
+            tip = mine(&ledger, &tip, &format!("Fork{}=1", i), 2000 + i).hash;
+        }
+        let chain = ledger.with_blocks(<[Block]>::to_vec);
+        assert_eq!(chain.len(), 252);
+        assert_eq!(chain[251].hash, tip);
+
+        for height in [150, 199, 200, 201, 252] {
+            let hash = &chain[height - 1].hash;
+            let (at, balances) = ledger.balances_at(hash).unwrap();
+            assert_eq!((at.height, at.on_main_chain), (height, true));
+            assert_eq!(
+                balances,
+                transactions::compute_balances(&chain[..height]),
+                "{}",
+                height
+            );
+        }
+
+        assert!(ledger.get_block_at_height(0).is_none());
+        assert!(ledger.get_block_at_height(253).is_none());
+        assert!(ledger.balances_at("unknown").is_none());
     }
 }
@@ -1,4 +1,10 @@
+pub mod clock;
+pub mod config;
+pub mod events;
 pub mod http;
 pub mod ledger;
+pub mod logging;
 pub mod node;
 pub mod peers;
+pub mod sim;
+pub mod sync;
@@ -0,0 +1,60 @@
+//! Sets up where the node's log events go.
+//!
+//! The crate logs through [`tracing`], so every event has a level, a target
+//! (the module it comes from) and fields such as `peer` or `block`. Events
+//! inside a node or request span also carry that span's fields, e.g. the
+//! node's address and the request ID.
+
+use serde::Deserialize;
+use std::io::IsTerminal;
+use std::str::FromStr;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human readable line per event.
+    Text,
+    /// One JSON object per event, for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Parses a filter such as `info,p2p::peers=debug`: a default level
+/// followed by per-module overrides.
+pub fn parse_filter(filter: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(filter).map_err(|e| e.to_string())
+}
+
+/// Installs the global subscriber printing events that pass `filter` to
+/// standard output. Does nothing if one is already installed, so embedding
+/// programs can set up their own.
+pub fn init(filter: &str, format: LogFormat) -> Result<(), String> {
+    let filter = parse_filter(filter)?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(std::io::stdout().is_terminal());
+
+    let _ = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .try_init(),
+    };
+
+    Ok(())
+}
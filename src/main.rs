@@ -1,12 +1,17 @@
-use p2p::node;
+use p2p::config::NodeConfig;
+use p2p::{logging, node};
+use std::process;
 
 fn main() {
-    let ip = std::env::var("NODE_IP").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let ip = ip.as_str();
-    let port: u16 = std::env::args()
-        .nth(1)
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(5000); // if port is not specified then default is 5000
+    let config = NodeConfig::from_env().unwrap_or_else(|e| {
+        eprintln!("[ERROR] Invalid configuration: {}", e);
+        process::exit(2);
+    });
 
-    node::start(ip, port);
+    if let Err(e) = logging::init(&config.log_filter, config.log_format) {
+        eprintln!("[ERROR] Invalid log filter: {}", e);
+        process::exit(2);
+    }
+
+    process::exit(node::start(config));
 }
@@ -1,90 +1,208 @@
-use crate::ledger::{self, AddBlockResult, Block};
-use crate::node::RUNTIME;
-use crate::node::protocol::{BlockDto, HashesDto, PeerDto, TransactionDto};
+use crate::ledger::{AddBlockResult, Block};
+use crate::node::Node;
+use crate::node::protocol::{BlockDto, HashesDto, PageDto, PeerDto, TransactionDto};
 use crate::node::route::Route;
-use crate::peers::{self, Peer, update_peer};
-use reqwest::Client;
+use crate::node::transport::Request;
+use crate::peers::Peer;
 use serde::Serialize;
-use std::sync::OnceLock;
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::task::JoinSet;
-use tokio::time::{Duration, sleep};
+use tracing::{Instrument, warn};
 
-static HTTP_CLIENT: OnceLock<Client> = OnceLock::new();
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
 
-fn http_client() -> &'static Client {
-    HTTP_CLIENT.get_or_init(|| {
-        Client::builder()
-            .pool_max_idle_per_host(256)
-            .build()
-            .unwrap()
-    })
-}
-
-async fn post_json_with_length<T: Serialize>(client: &Client, url: &str, value: &T) {
-    let body = match serde_json::to_string(value) {
-        Ok(b) => b,
-        Err(_) => return,
+async fn post_json<T: Serialize>(node: &Node, peer: &Peer, route: &Route, value: &T) {
+    let Some(request) = Request::post_json(route, value) else {
+        return;
     };
 
-    let _ = client
-        .post(url)
-        .header("Content-Type", "application/json")
-        .header("Content-Length", body.len())
-        .body(body)
-        .send()
-        .await;
+    let _ = node.send(peer, request).await;
 }
 
-pub async fn discover_peers() {
-    let peers = peers::select_random_peers();
+pub async fn discover_peers(node: &Arc<Node>) {
+    let peers = node.peers().select_gossip_peers();
     let mut set = JoinSet::new();
-    let client = http_client();
 
     for peer in peers {
-        let url = peer.to_url(&Route::GetPeers.to_path());
-
-        set.spawn(async move {
-            match client.get(&url).send().await {
-                Ok(r) => {
-                    if let Ok(resp) = r.json::<Vec<PeerDto>>().await {
-                        for p in resp {
-                            peers::add_peer(p.ip, p.port);
+        let node = Arc::clone(node);
+
+        set.spawn(
+            async move {
+                match node.send(&peer, Request::get(&Route::GetPeers)).await {
+                    Ok(r) => {
+                        if let Some(resp) = r.json::<Vec<PeerDto>>() {
+                            for p in resp {
+                                node.peers().add_peer(p.ip, p.port);
+                            }
                         }
                     }
+                    Err(_) => node.peers().update_peer(peer),
                 }
-                Err(_) => update_peer(peer),
             }
-        });
+            .in_current_span(),
+        );
     }
 
-    while let Some(_) = set.join_next().await {}
+    while set.join_next().await.is_some() {}
+
+    node.peers().refill_neighbours();
 }
 
-pub async fn fetch_blocks_from_peers() {
-    let peers = peers::select_random_peers();
-    let client = http_client();
+/// Pings every neighbour plus a few other known peers, so replacements for
+/// dead neighbours already have fresh liveness data.
+pub async fn ping_peers(node: &Arc<Node>) {
+    let peers = node.peers();
+    let mut targets = peers.outbound_neighbours();
+    targets.extend(peers.inbound_neighbours());
+    for peer in peers.select_random_peers().into_iter().take(8) {
+        if !targets.contains(&peer) {
+            targets.push(peer);
+        }
+    }
+
     let mut set = JoinSet::new();
 
-    for peer in peers {
-        set.spawn(async move {
-            let hashes_url = peer.to_url(&Route::GetHashes.to_path());
-            let Ok(resp) = client.get(&hashes_url).send().await else {
-                return;
-            };
+    for peer in targets {
+        let node = Arc::clone(node);
 
-            let Ok(data) = resp.json::<HashesDto>().await else {
-                return;
-            };
+        set.spawn(
+            async move {
+                let request = Request::get(&Route::GetPing).timeout(PING_TIMEOUT);
+                // Monotonic, unlike the wall clock, and still follows a
+                // paused runtime in simulations.
+                let started = tokio::time::Instant::now();
 
-            sync_with_peer_chain(&peer, data.hashes).await;
-        });
+                match node.send(&peer, request).await {
+                    Ok(r) if r.is_success() => {
+                        let rtt_ms = started.elapsed().as_millis() as u64;
+                        node.peers().record_pong(&peer, rtt_ms, node.clock().now());
+                    }
+                    _ => node.peers().update_peer(peer),
+                }
+            }
+            .in_current_span(),
+        );
     }
 
     while set.join_next().await.is_some() {}
+
+    node.peers().refill_neighbours();
+}
+
+/// Pages `fetch_all` follows before giving up on the rest of a list, so a
+/// peer can't keep a sync going forever. Enough for two million hashes.
+const MAX_FETCHED_PAGES: usize = 1000;
+
+enum FetchError {
+    /// The peer predates the paged route.
+    Unsupported,
+    Failed,
 }
 
-async fn sync_with_peer_chain(peer: &Peer, peer_hashes: Vec<String>) {
-    let local_hashes = ledger::get_all_block_hashes();
+/// Follows `next_cursor` through the pages of the list at `route` on
+/// `peer`, stopping after `MAX_FETCHED_PAGES` or when a page brings the
+/// cursor no further.
+async fn fetch_all<T: DeserializeOwned>(
+    node: &Node,
+    peer: &Peer,
+    route: &Route,
+) -> Result<Vec<T>, FetchError> {
+    let mut items = Vec::new();
+    let mut cursor: Option<String> = None;
+
+    for _ in 0..MAX_FETCHED_PAGES {
+        let request = match &cursor {
+            Some(cursor) => Request::get(route).param("cursor", cursor),
+            None => Request::get(route),
+        };
+        let resp = node
+            .send(peer, request)
+            .await
+            .map_err(|_| FetchError::Failed)?;
+
+        // Unknown routes get 501 from this node and 404 from others.
+        if cursor.is_none() && matches!(resp.status, 404 | 501) {
+            return Err(FetchError::Unsupported);
+        }
+        let page = resp.json::<PageDto<T>>().ok_or(FetchError::Failed)?;
+
+        let stalled = page.items.is_empty() || page.next_cursor == cursor;
+        items.extend(page.items);
+
+        match page.next_cursor {
+            Some(next) if !stalled => cursor = Some(next),
+            _ => return Ok(items),
+        }
+    }
+
+    warn!(peer = %peer.socket_addr(), route = ?route, "stopped paging after {} pages", MAX_FETCHED_PAGES);
+    Ok(items)
+}
+
+/// Main chain hashes of `peer`, from the single response older peers send
+/// when they don't know the paged route.
+async fn fetch_hashes(node: &Node, peer: &Peer) -> Option<Vec<String>> {
+    match fetch_all(node, peer, &Route::GetHashesPage).await {
+        Ok(hashes) => Some(hashes),
+        Err(FetchError::Unsupported) => {
+            let resp = node
+                .send(peer, Request::get(&Route::GetHashes))
+                .await
+                .ok()?;
+            resp.json::<HashesDto>().map(|dto| dto.hashes)
+        }
+        Err(FetchError::Failed) => None,
+    }
+}
+
+/// Pending transactions of `peer`, likewise falling back to the unpaged
+/// route.
+async fn fetch_transactions(node: &Node, peer: &Peer) -> Option<Vec<TransactionDto>> {
+    match fetch_all(node, peer, &Route::GetTransactionsPage).await {
+        Ok(txs) => Some(txs),
+        Err(FetchError::Unsupported) => {
+            let resp = node
+                .send(peer, Request::get(&Route::GetTransactions))
+                .await
+                .ok()?;
+            resp.json::<Vec<TransactionDto>>()
+        }
+        Err(FetchError::Failed) => None,
+    }
+}
+
+pub async fn fetch_blocks_from_peers(node: &Arc<Node>) {
+    let peers = node.peers().select_gossip_peers();
+    let mut set = JoinSet::new();
+
+    for peer in peers {
+        let node = Arc::clone(node);
+
+        set.spawn(
+            async move {
+                let Some(hashes) = fetch_hashes(&node, &peer).await else {
+                    return 0;
+                };
+
+                let height = hashes.len();
+                sync_with_peer_chain(&node, &peer, hashes).await;
+                height
+            }
+            .in_current_span(),
+        );
+    }
+
+    let mut best_peer_height = 0;
+    while let Some(height) = set.join_next().await {
+        best_peer_height = best_peer_height.max(height.unwrap_or(0));
+    }
+    node.metrics().set_best_peer_height(best_peer_height);
+}
+
+async fn sync_with_peer_chain(node: &Node, peer: &Peer, peer_hashes: Vec<String>) {
+    let local_hashes = node.ledger().get_all_block_hashes();
 
     if peer_hashes.len() <= local_hashes.len() {
         return;
@@ -99,151 +217,263 @@ async fn sync_with_peer_chain(peer: &Peer, peer_hashes: Vec<String>) {
     }
 
     for hash in peer_hashes.into_iter().skip(common_prefix_len) {
-        fetch_block(peer, &hash).await;
+        fetch_block(node, peer, &hash).await;
     }
 }
 
-async fn fetch_block(peer: &Peer, hash: &str) {
-    let client = http_client();
-    let url = peer.to_url(&Route::GetBlock(hash.into()).to_path());
+async fn fetch_block(node: &Node, peer: &Peer, hash: &str) {
+    let request = Request::get(&Route::GetBlock(hash.into()));
 
-    let Ok(resp) = client.get(&url).send().await else {
+    let Ok(resp) = node.send(peer, request).await else {
         return;
     };
 
-    if !resp.status().is_success() {
+    if !resp.is_success() {
         return;
     }
 
-    if let Ok(block) = resp.json::<BlockDto>().await {
-        ledger::add_block(&Block::from(&block));
+    if let Some(block) = resp.json::<BlockDto>() {
+        node.ledger().add_block(&Block::from(&block));
     }
 }
 
-pub fn broadcast_transaction(tx: TransactionDto) {
-    RUNTIME.spawn(async move {
-        let peers = peers::select_random_peers();
-        let client = http_client();
-        let mut set = JoinSet::new();
+pub fn broadcast_transaction(node: &Arc<Node>, tx: TransactionDto) {
+    let node = Arc::clone(node);
 
-        for peer in peers {
-            let url = peer.to_url(&Route::PostTransaction.to_path());
-            let tx = tx.clone();
+    node.runtime().clone().spawn(
+        async move {
+            let peers = node.peers().select_gossip_peers();
+            let mut set = JoinSet::new();
 
-            set.spawn(async move {
-                post_json_with_length(client, &url, &tx).await;
-            });
-        }
+            for peer in peers {
+                let node = Arc::clone(&node);
+                let tx = tx.clone();
+
+                set.spawn(
+                    async move {
+                        post_json(&node, &peer, &Route::PostTransaction, &tx).await;
+                    }
+                    .in_current_span(),
+                );
+            }
 
-        while set.join_next().await.is_some() {}
-    });
+            while set.join_next().await.is_some() {}
+        }
+        .in_current_span(),
+    );
 }
 
-pub fn broadcast_block(block: BlockDto) {
-    RUNTIME.spawn(async move {
-        let peers = peers::select_random_peers();
-        let client = http_client();
-        let mut set = JoinSet::new();
+pub fn broadcast_block(node: &Arc<Node>, block: BlockDto) {
+    let node = Arc::clone(node);
 
-        for peer in peers {
-            let url = peer.to_url(&Route::PostBlock.to_path());
-            let block = block.clone();
+    node.runtime().clone().spawn(
+        async move {
+            let peers = node.peers().select_gossip_peers();
+            let mut set = JoinSet::new();
 
-            set.spawn(async move {
-                post_json_with_length(client, &url, &block).await;
-            });
-        }
+            for peer in peers {
+                let node = Arc::clone(&node);
+                let block = block.clone();
 
-        while set.join_next().await.is_some() {}
-    });
-}
-pub async fn broadcast_self() {
-    RUNTIME.spawn(async move {
-        let peers = peers::select_random_peers();
-        let client = http_client();
-        let mut set = JoinSet::new();
-        let xself = peers::self_peer();
+                set.spawn(
+                    async move {
+                        post_json(&node, &peer, &Route::PostBlock, &block).await;
+                    }
+                    .in_current_span(),
+                );
+            }
 
-        for peer in peers {
-            let url = peer.to_url(&Route::PostPeers.to_path());
-            set.spawn(async move {
-                post_json_with_length(client, &url, &PeerDto::from(xself)).await;
-            });
+            while set.join_next().await.is_some() {}
         }
+        .in_current_span(),
+    );
+}
+
+pub async fn broadcast_self(node: &Arc<Node>) {
+    let peers = node.peers().select_gossip_peers();
+    let mut set = JoinSet::new();
+    let xself = PeerDto::from(node.peers().self_peer());
+
+    for peer in peers {
+        let node = Arc::clone(node);
+        let xself = xself.clone();
+
+        set.spawn(
+            async move {
+                post_json(&node, &peer, &Route::PostPeers, &xself).await;
+            }
+            .in_current_span(),
+        );
+    }
 
-        while set.join_next().await.is_some() {}
-    });
+    while set.join_next().await.is_some() {}
 }
 
-pub async fn peer_discovery_loop() {
+pub async fn peer_discovery_loop(node: Arc<Node>) {
     loop {
-        discover_peers().await;
-        sleep(Duration::from_secs(30)).await;
+        discover_peers(&node).await;
+
+        if !node.idle(node.config().discovery_interval()).await {
+            return;
+        }
     }
 }
 
-pub async fn block_sync_loop() {
+pub async fn block_sync_loop(node: Arc<Node>) {
     loop {
-        fetch_blocks_from_peers().await;
-        sleep(Duration::from_secs(30)).await;
+        fetch_blocks_from_peers(&node).await;
+
+        if !node.idle(node.config().block_sync_interval()).await {
+            return;
+        }
     }
 }
 
-pub async fn sync_transactions_from_peers() {
-    let peers = peers::select_random_peers();
-    let client = http_client();
+pub async fn sync_transactions_from_peers(node: &Arc<Node>) {
+    let peers = node.peers().select_gossip_peers();
     let mut set = JoinSet::new();
 
     for peer in peers {
-        set.spawn(async move {
-            let url = peer.to_url(&Route::GetTransactions.to_path());
-            let Ok(resp) = client.get(&url).send().await else {
-                return;
-            };
+        let node = Arc::clone(node);
 
-            let Ok(txs) = resp.json::<Vec<TransactionDto>>().await else {
-                return;
-            };
+        set.spawn(
+            async move {
+                let Some(txs) = fetch_transactions(&node, &peer).await else {
+                    return;
+                };
 
-            for tx in &txs {
-                let t = tx.into();
-                ledger::add_transaction(&t);
+                for tx in &txs {
+                    let t = tx.into();
+                    node.ledger().add_transaction(&t);
+                }
             }
-        });
+            .in_current_span(),
+        );
     }
 
     while set.join_next().await.is_some() {}
 }
 
-pub async fn transactions_sync_loop() {
+pub async fn transactions_sync_loop(node: Arc<Node>) {
     loop {
-        sync_transactions_from_peers().await;
-        sleep(Duration::from_secs(15)).await;
+        sync_transactions_from_peers(&node).await;
+
+        if !node.idle(node.config().tx_sync_interval()).await {
+            return;
+        }
     }
 }
 
-pub async fn block_creation_loop() {
-    loop {
-        sleep(Duration::from_secs(60)).await;
-
-        let pending = ledger::get_transactions_for_mining(1000);
+pub async fn block_creation_loop(node: Arc<Node>) {
+    while node.idle(node.config().block_creation_interval()).await {
+        let ledger = node.ledger();
+        let pending = ledger.get_transactions_for_mining(node.config().max_block_txs);
         if pending.is_empty() {
             continue;
         }
 
-        let prev_hash = ledger::last_block_hash();
-        let timestamp = ledger::now();
-        let block = Block::new(prev_hash, pending, timestamp);
+        let prev_hash = ledger.last_block_hash();
+        let timestamp = node.clock().now();
+        let difficulty = ledger.difficulty();
+
+        // Proof of work can take a while; keep it off the runtime's workers
+        // so requests and sync carry on meanwhile.
+        let mined = node
+            .runtime()
+            .spawn_blocking(move || {
+                let started = Instant::now();
+                let block = Block::new(prev_hash, pending, timestamp, difficulty);
+                (block, started.elapsed())
+            })
+            .await;
+        let Ok((block, elapsed)) = mined else {
+            continue;
+        };
+        node.metrics().record_mined_block(block.nonce + 1, elapsed);
+
+        if matches!(ledger.add_block(&block), AddBlockResult::Added) {
+            broadcast_block(&node, BlockDto::from(&block));
+        }
+    }
+}
+
+pub async fn ping_loop(node: Arc<Node>) {
+    loop {
+        ping_peers(&node).await;
 
-        if matches!(ledger::add_block(&block), AddBlockResult::Added) {
-            broadcast_block(BlockDto::from(&block));
+        if !node.idle(node.config().ping_interval()).await {
+            return;
         }
     }
 }
 
-pub async fn advertisement_loop() {
+pub async fn neighbour_rotation_loop(node: Arc<Node>) {
+    while node.idle(node.config().neighbour_rotation_interval()).await {
+        node.peers().rotate_neighbours();
+    }
+}
+
+pub async fn advertisement_loop(node: Arc<Node>) {
     loop {
-        broadcast_self().await;
-        sleep(Duration::from_secs(15)).await;
+        broadcast_self(&node).await;
+
+        if !node.idle(node.config().advertisement_interval()).await {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::transport::{Response, ResponseFuture, Transport};
+    use crate::sim::sim_config;
+
+    /// A peer whose paged routes answer `status` and whose unpaged ones
+    /// answer like nodes did before paging.
+    struct Unpaged {
+        status: u16,
+    }
+
+    impl Transport for Unpaged {
+        fn send(&self, _from: &Peer, _to: &Peer, request: Request) -> ResponseFuture {
+            let (status, body) = match request.route {
+                "/hashes" => (200, r#"{"hashes":["a","b"]}"#),
+                "/transactions" => (200, r#"[{"hash":"t","data":"Bob=1","timestamp":1}]"#),
+                _ => (self.status, ""),
+            };
+            Box::pin(async move {
+                Ok(Response {
+                    status,
+                    body: body.into(),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn peers_without_paged_routes_are_read_unpaged() {
+        let peer = Peer::new("10.0.0.2".into(), 5000);
+
+        for status in [404, 501] {
+            let node = Node::builder(sim_config())
+                .transport(Arc::new(Unpaged { status }))
+                .build();
+
+            assert_eq!(
+                fetch_hashes(&node, &peer).await,
+                Some(vec!["a".to_string(), "b".to_string()])
+            );
+            let txs = fetch_transactions(&node, &peer).await.unwrap();
+            assert_eq!(txs.len(), 1);
+            assert_eq!(txs[0].data, "Bob=1");
+        }
+
+        // Other errors mean the peer failed, not that it is old.
+        let node = Node::builder(sim_config())
+            .transport(Arc::new(Unpaged { status: 500 }))
+            .build();
+        assert_eq!(fetch_hashes(&node, &peer).await, None);
+        assert!(fetch_transactions(&node, &peer).await.is_none());
     }
 }
@@ -0,0 +1,439 @@
+//! Counters behind `GET /metrics`, rendered in the Prometheus text
+//! exposition format.
+
+use crate::http::middleware::Middleware;
+use crate::http::server::{HttpHandler, HttpRequest, HttpResult};
+use crate::node::Node;
+use crate::node::route::Route;
+use crate::sync::lock;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds, in seconds, of the HTTP latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Label used for requests that matched no route.
+const UNMATCHED: &str = "unmatched";
+
+/// Method and route pattern, e.g. `("POST", "/blocks")`.
+type RouteKey = (&'static str, &'static str);
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative; the last one is `+Inf`.
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        self.buckets[bucket] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// Counters a node keeps about itself beyond what its ledger and peer table
+/// already know.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    sent: Mutex<BTreeMap<RouteKey, u64>>,
+    received: Mutex<BTreeMap<RouteKey, u64>>,
+    latency: Mutex<BTreeMap<RouteKey, Histogram>>,
+    blocks_mined: AtomicU64,
+    hashes_computed: AtomicU64,
+    /// Hash rate of the last mined block, stored as `f64` bits.
+    hash_rate: AtomicU64,
+    /// Longest chain reported by a peer in the last sync round.
+    best_peer_height: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn record_sent(&self, method: &'static str, route: &'static str) {
+        *lock(&self.sent).entry((method, route)).or_default() += 1;
+    }
+
+    pub fn record_received(&self, key: RouteKey, elapsed: Duration) {
+        *lock(&self.received).entry(key).or_default() += 1;
+        lock(&self.latency)
+            .entry(key)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Records a mined block that took `attempts` hashes and `elapsed` time.
+    pub fn record_mined_block(&self, attempts: u64, elapsed: Duration) {
+        self.blocks_mined.fetch_add(1, Ordering::Relaxed);
+        self.hashes_computed.fetch_add(attempts, Ordering::Relaxed);
+
+        let rate = attempts as f64 / elapsed.as_secs_f64().max(1e-9);
+        self.hash_rate.store(rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_best_peer_height(&self, height: usize) {
+        self.best_peer_height.store(height, Ordering::Relaxed);
+    }
+}
+
+/// Middleware counting received requests and timing them per route.
+pub struct RecordMetrics {
+    node: Arc<Node>,
+}
+
+impl RecordMetrics {
+    pub fn new(node: Arc<Node>) -> Self {
+        RecordMetrics { node }
+    }
+}
+
+impl Middleware for RecordMetrics {
+    fn handle(&self, request: HttpRequest, next: &dyn HttpHandler) -> HttpResult {
+        let key = match Route::parse(&request.method) {
+            Some(route) => (route.method(), route.pattern()),
+            None => (request.method.name(), UNMATCHED),
+        };
+        let started = Instant::now();
+
+        let result = next.handle(request);
+
+        self.node.metrics().record_received(key, started.elapsed());
+        result
+    }
+}
+
+/// Renders every metric of `node` in the text exposition format.
+pub fn render(node: &Node) -> String {
+    let mut out = Exposition::default();
+    let ledger = node.ledger();
+    let metrics = node.metrics();
+
+    let height = ledger.chain_len();
+    out.metric("p2p_chain_height", "gauge", "Blocks on the main chain.");
+    out.sample("p2p_chain_height", &[], height);
+
+    let reorgs = ledger.reorg_stats();
+    out.metric(
+        "p2p_reorgs_total",
+        "counter",
+        "Switches of the main chain to another branch.",
+    );
+    out.sample("p2p_reorgs_total", &[], reorgs.count);
+    out.metric(
+        "p2p_reorg_depth_last",
+        "gauge",
+        "Blocks dropped by the last reorg.",
+    );
+    out.sample("p2p_reorg_depth_last", &[], reorgs.last_depth);
+    out.metric(
+        "p2p_reorg_depth_max",
+        "gauge",
+        "Blocks dropped by the deepest reorg.",
+    );
+    out.sample("p2p_reorg_depth_max", &[], reorgs.max_depth);
+
+    out.metric(
+        "p2p_orphan_blocks",
+        "gauge",
+        "Blocks waiting for their parent.",
+    );
+    out.sample("p2p_orphan_blocks", &[], ledger.orphan_count());
+    out.metric(
+        "p2p_mempool_transactions",
+        "gauge",
+        "Transactions not yet on the main chain.",
+    );
+    out.sample("p2p_mempool_transactions", &[], ledger.pending_txs_len());
+
+    let peers = node.peers();
+    let details = peers.get_peer_details();
+    let failing = details
+        .iter()
+        .filter(|(_, stats)| stats.failure_streak > 0)
+        .count();
+    out.metric("p2p_peers", "gauge", "Peers by state.");
+    out.sample("p2p_peers", &[("state", "known")], details.len());
+    out.sample(
+        "p2p_peers",
+        &[("state", "outbound")],
+        peers.outbound_neighbours().len(),
+    );
+    out.sample(
+        "p2p_peers",
+        &[("state", "inbound")],
+        peers.inbound_neighbours().len(),
+    );
+    out.sample("p2p_peers", &[("state", "failing")], failing);
+
+    out.metric(
+        "p2p_messages_sent_total",
+        "counter",
+        "Requests sent to peers.",
+    );
+    for (&(method, route), count) in lock(&metrics.sent).iter() {
+        out.sample(
+            "p2p_messages_sent_total",
+            &[("method", method), ("route", route)],
+            count,
+        );
+    }
+    out.metric(
+        "p2p_messages_received_total",
+        "counter",
+        "Requests received over HTTP.",
+    );
+    for (&(method, route), count) in lock(&metrics.received).iter() {
+        out.sample(
+            "p2p_messages_received_total",
+            &[("method", method), ("route", route)],
+            count,
+        );
+    }
+
+    out.metric(
+        "p2p_http_request_duration_seconds",
+        "histogram",
+        "Time spent handling HTTP requests.",
+    );
+    for (&(method, route), histogram) in lock(&metrics.latency).iter() {
+        out.histogram(
+            "p2p_http_request_duration_seconds",
+            &[("method", method), ("route", route)],
+            histogram,
+        );
+    }
+
+    out.metric(
+        "p2p_blocks_mined_total",
+        "counter",
+        "Blocks mined by this node.",
+    );
+    out.sample(
+        "p2p_blocks_mined_total",
+        &[],
+        metrics.blocks_mined.load(Ordering::Relaxed),
+    );
+    out.metric(
+        "p2p_mining_hashes_total",
+        "counter",
+        "Hashes computed while mining.",
+    );
+    out.sample(
+        "p2p_mining_hashes_total",
+        &[],
+        metrics.hashes_computed.load(Ordering::Relaxed),
+    );
+    out.metric(
+        "p2p_mining_hash_rate",
+        "gauge",
+        "Hashes per second while mining the last block.",
+    );
+    out.sample(
+        "p2p_mining_hash_rate",
+        &[],
+        f64::from_bits(metrics.hash_rate.load(Ordering::Relaxed)),
+    );
+
+    let best_peer_height = metrics.best_peer_height.load(Ordering::Relaxed);
+    out.metric(
+        "p2p_sync_lag_blocks",
+        "gauge",
+        "Blocks the longest peer chain is ahead of ours.",
+    );
+    out.sample(
+        "p2p_sync_lag_blocks",
+        &[],
+        best_peer_height.saturating_sub(height),
+    );
+
+    if let Some(pool) = node.http_pool_stats() {
+        out.metric(
+            "p2p_http_pool_queued",
+            "gauge",
+            "Requests waiting for an HTTP worker.",
+        );
+        out.sample("p2p_http_pool_queued", &[], pool.queued);
+        out.metric(
+            "p2p_http_pool_active",
+            "gauge",
+            "Requests being handled by HTTP workers.",
+        );
+        out.sample("p2p_http_pool_active", &[], pool.active);
+        out.metric(
+            "p2p_http_pool_rejected_total",
+            "counter",
+            "Requests rejected with a full queue.",
+        );
+        out.sample("p2p_http_pool_rejected_total", &[], pool.rejected);
+    }
+
+    out.text
+}
+
+#[derive(Default)]
+struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    fn metric(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        let _ = writeln!(self.text, "{}{} {}", name, format_labels(labels), value);
+    }
+
+    fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let mut cumulative = 0;
+
+        for (i, count) in histogram.buckets.iter().enumerate() {
+            cumulative += count;
+            let bound = LATENCY_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), |b| b.to_string());
+
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &bound));
+            self.sample(&format!("{}_bucket", name), &bucket_labels, cumulative);
+        }
+
+        self.sample(&format!("{}_sum", name), labels, histogram.sum);
+        self.sample(&format!("{}_count", name), labels, histogram.count);
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+
+    format!("{{{}}}", pairs.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::sim_config;
+
+    fn lines_starting_with<'a>(text: &'a str, prefix: &str) -> Vec<&'a str> {
+        text.lines().filter(|l| l.starts_with(prefix)).collect()
+    }
+
+    #[tokio::test]
+    async fn render_lists_recorded_events() {
+        let node = Node::builder(sim_config()).build();
+        let metrics = node.metrics();
+        metrics.record_sent("POST", "/blocks");
+        metrics.record_sent("POST", "/blocks");
+        metrics.record_sent("GET", "/hashes");
+        metrics.record_received(("GET", "/status"), Duration::from_millis(3));
+        metrics.record_received(("GET", "/status"), Duration::from_secs(2));
+        metrics.record_received(("GET", UNMATCHED), Duration::from_micros(500));
+        metrics.record_mined_block(1000, Duration::from_millis(500));
+
+        let text = render(&node);
+
+        assert_eq!(
+            lines_starting_with(&text, "p2p_messages_sent_total"),
+            [
+                r#"p2p_messages_sent_total{method="GET",route="/hashes"} 1"#,
+                r#"p2p_messages_sent_total{method="POST",route="/blocks"} 2"#,
+            ]
+        );
+        assert_eq!(
+            lines_starting_with(&text, "p2p_messages_received_total"),
+            [
+                r#"p2p_messages_received_total{method="GET",route="/status"} 2"#,
+                r#"p2p_messages_received_total{method="GET",route="unmatched"} 1"#,
+            ]
+        );
+        assert_eq!(
+            lines_starting_with(
+                &text,
+                r#"p2p_http_request_duration_seconds_bucket{method="GET",route="/status""#
+            ),
+            [
+                r#"p2p_http_request_duration_seconds_bucket{method="GET",route="/status",le="0.001"} 0"#,
+                r#"p2p_http_request_duration_seconds_bucket{method="GET",route="/status",le="0.0025"} 0"#,
+                r#"p2p_http_request_duration_seconds_bucket{method="GET",route="/status",le="0.005"} 1"#,
+                r#"p2p_http_request_duration_seconds_bucket{method="GET",route="/status",le="0.01"} 1"#,
+                r#"p2p_http_request_duration_seconds_bucket{method="GET",route="/status",le="0.025"} 1"#,
+                r#"p2p_http_request_duration_seconds_bucket{method="GET",route="/status",le="0.05"} 1"#,
+                r#"p2p_http_request_duration_seconds_bucket{method="GET",route="/status",le="0.1"} 1"#,
+                r#"p2p_http_request_duration_seconds_bucket{method="GET",route="/status",le="0.25"} 1"#,
+                r#"p2p_http_request_duration_seconds_bucket{method="GET",route="/status",le="0.5"} 1"#,
+                r#"p2p_http_request_duration_seconds_bucket{method="GET",route="/status",le="1"} 1"#,
+                r#"p2p_http_request_duration_seconds_bucket{method="GET",route="/status",le="+Inf"} 2"#,
+            ]
+        );
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in [
+            r#"p2p_http_request_duration_seconds_sum{method="GET",route="/status"} 2.003"#,
+            r#"p2p_http_request_duration_seconds_count{method="GET",route="/status"} 2"#,
+            r#"p2p_http_request_duration_seconds_bucket{method="GET",route="unmatched",le="0.001"} 1"#,
+            "# TYPE p2p_http_request_duration_seconds histogram",
+            "# TYPE p2p_messages_sent_total counter",
+            "p2p_chain_height 1",
+            "p2p_blocks_mined_total 1",
+            "p2p_mining_hashes_total 1000",
+            "p2p_mining_hash_rate 2000",
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "{} missing from\n{}",
+                expected,
+                text
+            );
+        }
+
+        // Counters end in `_total`, and every sample belongs to a declared
+        // metric.
+        let declared: Vec<(&str, &str)> = text
+            .lines()
+            .filter_map(|l| l.strip_prefix("# TYPE "))
+            .filter_map(|l| l.split_once(' '))
+            .collect();
+        for (name, kind) in &declared {
+            assert_eq!(*kind == "counter", name.ends_with("_total"), "{}", name);
+        }
+        for sample in text.lines().filter(|l| !l.starts_with('#')) {
+            let name = sample.split(['{', ' ']).next().unwrap();
+            assert!(
+                declared.iter().any(|(metric, kind)| name == *metric
+                    || (*kind == "histogram" && name.starts_with(metric))),
+                "{} was not declared",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(format_labels(&[]), "");
+        assert_eq!(
+            format_labels(&[("route", "a\"b\\c\nd"), ("method", "GET")]),
+            r#"{route="a\"b\\c\nd",method="GET"}"#
+        );
+    }
+}
@@ -1,88 +1,379 @@
 pub mod client;
+pub mod metrics;
+pub mod page;
 pub mod protocol;
 pub mod route;
 pub mod server;
 pub mod transactions;
+pub mod transport;
 
+use crate::clock::{Clock, SystemClock};
+use crate::config::NodeConfig;
+use crate::events::Events;
 use crate::http;
-use crate::ledger;
-use crate::node;
-use crate::peers;
-use lazy_static::lazy_static;
+use crate::http::middleware::{CatchPanic, Cors, HttpHandlerExt, Logger, RateLimit};
+use crate::http::threadpool::{PoolMetrics, PoolStats};
+use crate::ledger::Ledger;
+use crate::peers::{Peer, PeerTable};
+use crate::sync::lock;
+use metrics::{Metrics, RecordMetrics};
 use protocol::PeerDto;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use route::Route;
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 use std::{fs, process};
-use tokio::runtime::Runtime;
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tracing::{Instrument, Span, debug, error, info, info_span, warn};
+use transport::{HttpTransport, Request, Response, Transport, TransportError};
 
-lazy_static! {
-    static ref RUNTIME: Runtime =
-        Runtime::new().expect("[ERROR] Async runtime could not be started");
+/// A single ledger node: its chain, mempool, peers and the runtime its
+/// background jobs run on. Several nodes can live in one process.
+pub struct Node {
+    config: NodeConfig,
+    ledger: Ledger,
+    peers: PeerTable,
+    runtime: Handle,
+    transport: Arc<dyn Transport>,
+    clock: Arc<dyn Clock>,
+    background_jobs: Mutex<Vec<JoinHandle<()>>>,
+    shutdown: watch::Sender<bool>,
+    /// Counters of the HTTP thread pool, once `serve` has started it.
+    http_pool: OnceLock<Arc<PoolMetrics>>,
+    metrics: Metrics,
+    /// Shared by the ledger and peer table, read by `GET /events`.
+    events: Events,
+    /// Parent span of everything the node logs, carrying its address.
+    span: Span,
 }
 
-pub fn start(ip: &str, port: u16) {
-    let addr = format!("{ip}:{port}");
+pub struct NodeBuilder {
+    config: NodeConfig,
+    runtime: Option<Handle>,
+    transport: Option<Arc<dyn Transport>>,
+    clock: Option<Arc<dyn Clock>>,
+    bootstrap_peers: Vec<(String, u16)>,
+}
+
+impl NodeBuilder {
+    /// Runtime used for background jobs and outgoing requests. Defaults to
+    /// the runtime `build` is called from.
+    pub fn runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// How requests reach other nodes. Defaults to HTTP.
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
 
-    println!("========================================");
-    println!("P2P Distributed Ledger Node");
-    println!("Starting on: {}", addr);
-    println!("========================================");
-    println!();
+    /// Time source for timestamps and loop intervals. Defaults to the
+    /// system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
 
-    peers::set_self_peer(ip.into(), port);
-    println!("[NODE] Added {}:{} as self", ip, port);
+    pub fn bootstrap_peers(mut self, peers: Vec<(String, u16)>) -> Self {
+        self.bootstrap_peers = peers;
+        self
+    }
 
-    load_peers();
-    println!("[NODE] Peers loaded from config");
+    pub fn build(self) -> Arc<Node> {
+        let config = self.config;
+        let runtime = self.runtime.unwrap_or_else(Handle::current);
 
-    ledger::init_genesis_block();
-    println!("[LEDGER] Genesis block created");
+        let self_peer = Peer::new(config.ip.clone(), config.port);
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let span = info_span!("node", addr = %config.addr());
+        let _entered = span.enter();
 
-    start_async_background_jobs();
-    println!("[NODE] Started background jobs");
+        let events = Events::new();
+        let peers = PeerTable::new(self_peer, config.neighbours(), rng).with_events(events.clone());
+        peers.add_bootstrap_peers(self.bootstrap_peers);
+        peers.refill_neighbours();
 
-    http::server::start(&addr, node::server::RequestHandler);
+        let ledger = Ledger::new(config.difficulty).with_events(events.clone());
+        debug!(genesis = %ledger.last_block_hash(), "created genesis block");
+
+        let transport = self
+            .transport
+            .unwrap_or_else(|| Arc::new(HttpTransport::new()));
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+
+        Arc::new(Node {
+            config,
+            ledger,
+            peers,
+            runtime,
+            transport,
+            clock,
+            background_jobs: Mutex::new(Vec::new()),
+            shutdown: watch::Sender::new(false),
+            http_pool: OnceLock::new(),
+            metrics: Metrics::default(),
+            events,
+            span: span.clone(),
+        })
+    }
 }
 
-fn start_async_background_jobs() {
-    RUNTIME.spawn(async {
-        node::client::peer_discovery_loop().await;
-    });
+impl Node {
+    pub fn builder(config: NodeConfig) -> NodeBuilder {
+        NodeBuilder {
+            config,
+            runtime: None,
+            transport: None,
+            clock: None,
+            bootstrap_peers: Vec::new(),
+        }
+    }
 
-    RUNTIME.spawn(async {
-        node::client::block_sync_loop().await;
-    });
+    pub fn config(&self) -> &NodeConfig {
+        &self.config
+    }
 
-    RUNTIME.spawn(async {
-        node::client::block_creation_loop().await;
-    });
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
 
-    RUNTIME.spawn(async {
-        node::client::transactions_sync_loop().await;
-    });
+    pub fn peers(&self) -> &PeerTable {
+        &self.peers
+    }
 
-    RUNTIME.spawn(async {
-        node::client::advertisement_loop().await;
-    });
+    pub fn runtime(&self) -> &Handle {
+        &self.runtime
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Ledger and peer table changes, as streamed by `GET /events`.
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// Span to run the node's tasks in, so their log events say which node
+    /// they come from.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn http_pool_stats(&self) -> Option<PoolStats> {
+        self.http_pool.get().map(|metrics| metrics.snapshot())
+    }
+
+    /// Sends `request` to `peer` over the node's transport.
+    pub async fn send(&self, peer: &Peer, request: Request) -> Result<Response, TransportError> {
+        self.metrics
+            .record_sent(request.method.name(), request.route);
+        let route = request.route;
+
+        self.transport
+            .send(self.peers.self_peer(), peer, request)
+            .await
+            .inspect_err(
+                |e| debug!(peer = %peer.socket_addr(), route, error = ?e, "request failed"),
+            )
+    }
+
+    pub fn start_background_jobs(self: &Arc<Self>) {
+        let jobs = [
+            self.spawn(client::peer_discovery_loop(Arc::clone(self))),
+            self.spawn(client::block_sync_loop(Arc::clone(self))),
+            self.spawn(client::block_creation_loop(Arc::clone(self))),
+            self.spawn(client::transactions_sync_loop(Arc::clone(self))),
+            self.spawn(client::advertisement_loop(Arc::clone(self))),
+            self.spawn(client::neighbour_rotation_loop(Arc::clone(self))),
+            self.spawn(client::ping_loop(Arc::clone(self))),
+        ];
+
+        lock(&self.background_jobs).extend(jobs);
+    }
+
+    /// Spawns `task` on the node's runtime inside the node's span.
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.runtime.spawn(task.instrument(self.span.clone()))
+    }
+
+    /// Aborts the loops started by `start_background_jobs`, wherever they
+    /// are.
+    pub fn stop_background_jobs(&self) {
+        for job in lock(&self.background_jobs).drain(..) {
+            job.abort();
+        }
+    }
+
+    /// Asks the server and background loops to stop. Loops finish the round
+    /// they are on; `serve` stops accepting and drains open requests.
+    pub fn begin_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Completes once `begin_shutdown` has been called.
+    pub async fn shutdown_requested(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+        let _ = shutdown.wait_for(|&s| s).await;
+    }
+
+    /// Waits `duration` between rounds of a background loop. Returns
+    /// `false`, possibly early, once the node is shutting down.
+    pub async fn idle(&self, duration: Duration) -> bool {
+        tokio::select! {
+            _ = self.clock.sleep(duration) => !self.is_shutting_down(),
+            _ = self.shutdown_requested() => false,
+        }
+    }
+
+    /// Begins shutting down and waits up to `shutdown_timeout` for the
+    /// background loops to finish, aborting those that don't.
+    pub async fn shutdown(&self) {
+        self.begin_shutdown();
+
+        let jobs: Vec<_> = lock(&self.background_jobs).drain(..).collect();
+        let aborts: Vec<_> = jobs.iter().map(|job| job.abort_handle()).collect();
+
+        let finished = timeout(self.config.shutdown_timeout(), async {
+            for job in jobs {
+                let _ = job.await;
+            }
+        })
+        .await;
+
+        if finished.is_err() {
+            warn!("background jobs did not stop in time, aborting them");
+            for job in aborts {
+                job.abort();
+            }
+        }
+    }
+
+    /// Serves the node's HTTP API until the node shuts down or the listener
+    /// fails. Requests are handled on a pool of `http_threads` threads.
+    pub async fn serve(self: &Arc<Self>) -> std::io::Result<()> {
+        let rate_limit = RateLimit::new(self.config.rate_limits(), |req| {
+            Route::parse(&req.method).and_then(|route| route.rate_class())
+        });
+
+        let handler = server::RequestHandler::new(Arc::clone(self))
+            .layer(CatchPanic)
+            .layer(rate_limit)
+            .layer(RecordMetrics::new(Arc::clone(self)))
+            .layer(Cors::default())
+            .layer(Logger);
+        let handler = http::server::Blocking::new(
+            handler,
+            self.config.http_threads,
+            self.config.http_queue_size,
+        );
+        let _ = self.http_pool.set(handler.metrics());
+
+        http::server::start(
+            &self.config.addr(),
+            self.config.server(),
+            handler,
+            self.shutdown_requested(),
+        )
+        .instrument(self.span.clone())
+        .await
+    }
 }
 
-fn load_peers() {
-    let bootstrap_peers = load_peer_config();
-    let peers = bootstrap_peers
-        .iter()
-        .map(|p| (p.ip.clone(), p.port))
+/// Runs a node until it is stopped with SIGINT or SIGTERM and returns the
+/// process exit code.
+pub fn start(config: NodeConfig) -> i32 {
+    let runtime = Runtime::new().expect("[ERROR] Async runtime could not be started");
+
+    info!(addr = %config.addr(), "starting P2P distributed ledger node");
+
+    let bootstrap_peers = load_peer_config(&config.peers_file)
+        .into_iter()
+        .map(|p| (p.ip, p.port))
         .collect();
-    peers::add_bootstrap_peers(peers);
+
+    let node = Node::builder(config)
+        .runtime(runtime.handle().clone())
+        .bootstrap_peers(bootstrap_peers)
+        .build();
+
+    node.start_background_jobs();
+    info!("started background jobs");
+
+    runtime.spawn(shutdown_on_signal(Arc::clone(&node)));
+
+    let served = runtime.block_on(node.serve());
+    runtime.block_on(node.shutdown());
+
+    // The node keeps no state on disk, so there is nothing left to flush.
+    match served {
+        Ok(()) => {
+            info!("stopped");
+            0
+        }
+        Err(_) => 1,
+    }
 }
 
-fn load_peer_config() -> Vec<PeerDto> {
-    let config_file = "peers_config.json";
+/// Starts a graceful shutdown on the first signal and exits right away on
+/// the second.
+async fn shutdown_on_signal(node: Arc<Node>) {
+    wait_for_signal().await;
+    info!("shutting down, press Ctrl+C again to exit immediately");
+    node.begin_shutdown();
+
+    wait_for_signal().await;
+    error!("shutdown interrupted");
+    process::exit(130);
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("[ERROR] Could not listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
 
+fn load_peer_config(config_file: &str) -> Vec<PeerDto> {
     let content = fs::read_to_string(config_file).unwrap_or_else(|e| {
-        eprintln!("[ERROR] Failed to read {}: {}", config_file, e);
+        error!(file = config_file, error = %e, "failed to read peer config");
         process::exit(1);
     });
 
     serde_json::from_str(&content).unwrap_or_else(|e| {
-        eprintln!("[ERROR] Could not parse {}: {}", config_file, e);
+        error!(file = config_file, error = %e, "could not parse peer config");
         process::exit(1);
     })
 }
@@ -0,0 +1,124 @@
+//! Cursor pagination and filters shared by the list endpoints.
+//!
+//! A page holds at most `limit` items and a `next_cursor` pointing past its
+//! last item. Cursors are opaque to clients: an endpoint encodes the sort
+//! key of the last item it returned (a name, a height, ...) and the next
+//! request continues right after that key, so items added in between don't
+//! shift the following pages.
+
+use crate::http::server::{InvalidParam, Query};
+use crate::node::protocol::PageDto;
+use crate::node::transactions::ParsedTx;
+use std::str::FromStr;
+
+pub struct PageRequest {
+    /// Key of the last item of the previous page.
+    pub after: Option<String>,
+    pub limit: usize,
+}
+
+impl PageRequest {
+    /// Reads `cursor` and `limit`. `limit` defaults to, and is capped at,
+    /// `max`.
+    pub fn from_query(query: &Query, max: usize) -> Result<Self, InvalidParam> {
+        let after = match query.get("cursor") {
+            Some(cursor) => Some(decode(cursor).ok_or_else(|| InvalidParam("cursor".into()))?),
+            None => None,
+        };
+
+        let limit = match query.parse_param::<usize>("limit")? {
+            Some(0) => return Err(InvalidParam("limit".into())),
+            limit => limit.unwrap_or(max).min(max),
+        };
+
+        Ok(PageRequest { after, limit })
+    }
+
+    /// The key of the previous page's last item parsed as `T`, for
+    /// endpoints ordered by something other than a string.
+    pub fn after_as<T: FromStr>(&self) -> Result<Option<T>, InvalidParam> {
+        self.after
+            .as_deref()
+            .map(|key| key.parse().map_err(|_| InvalidParam("cursor".into())))
+            .transpose()
+    }
+
+    /// Cuts the page out of `items`, which must be sorted by `key` and
+    /// already start after the cursor.
+    pub fn page<T>(
+        &self,
+        items: impl Iterator<Item = T>,
+        key: impl Fn(&T) -> String,
+    ) -> PageDto<T> {
+        // One extra item is read to tell whether another page follows.
+        let mut items: Vec<T> = items.take(self.limit + 1).collect();
+
+        let next_cursor = if items.len() > self.limit {
+            items.truncate(self.limit);
+            items.last().map(|item| encode(&key(item)))
+        } else {
+            None
+        };
+
+        PageDto { items, next_cursor }
+    }
+}
+
+pub fn encode(key: &str) -> String {
+    hex::encode(key)
+}
+
+fn decode(cursor: &str) -> Option<String> {
+    String::from_utf8(hex::decode(cursor).ok()?).ok()
+}
+
+/// `user`, `min_amount`, `max_amount`, `min_height` and `max_height`
+/// query parameters. Each endpoint applies the ones that make sense for
+/// its items; all bounds are inclusive.
+#[derive(Debug, Default)]
+pub struct Filters {
+    pub user: Option<String>,
+    pub min_amount: Option<i64>,
+    pub max_amount: Option<i64>,
+    pub min_height: Option<usize>,
+    pub max_height: Option<usize>,
+}
+
+impl Filters {
+    pub fn from_query(query: &Query) -> Result<Self, InvalidParam> {
+        Ok(Filters {
+            user: query.get("user").map(str::to_string),
+            min_amount: query.parse_param("min_amount")?,
+            max_amount: query.parse_param("max_amount")?,
+            min_height: query.parse_param("min_height")?,
+            max_height: query.parse_param("max_height")?,
+        })
+    }
+
+    pub fn amount(&self, amount: i64) -> bool {
+        self.min_amount.is_none_or(|min| amount >= min)
+            && self.max_amount.is_none_or(|max| amount <= max)
+    }
+
+    pub fn height(&self, height: usize) -> bool {
+        self.min_height.is_none_or(|min| height >= min)
+            && self.max_height.is_none_or(|max| height <= max)
+    }
+
+    /// Whether `tx` involves the filtered user and its amount, the sum of a
+    /// transfer or the starting balance of a new user, is in range.
+    /// Transactions that don't parse only pass when neither is filtered.
+    pub fn transaction(&self, tx: Option<&ParsedTx>) -> bool {
+        let (users, amount) = match tx {
+            Some(ParsedTx::CreateUser { name, balance }) => ([name, name], *balance),
+            Some(ParsedTx::Transfer { from, to, sum }) => ([from, to], *sum),
+            None => {
+                return self.user.is_none()
+                    && self.min_amount.is_none()
+                    && self.max_amount.is_none();
+            }
+        };
+
+        self.user.as_ref().is_none_or(|user| users.contains(&user)) && self.amount(amount)
+    }
+}
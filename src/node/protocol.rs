@@ -1,4 +1,5 @@
-use crate::ledger::{Block, Transaction};
+use crate::http::threadpool::PoolStats;
+use crate::ledger::{Block, BlockRef, Transaction};
 use crate::peers::Peer;
 use serde::{Deserialize, Serialize};
 
@@ -8,9 +9,13 @@ pub struct StatusDto {
     pub last_block_hash: String,
     pub pending_txs_num: usize,
     pub known_peers: Vec<PeerDto>,
+    pub outbound_peers: Vec<PeerDto>,
+    pub inbound_peers: Vec<PeerDto>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_pool: Option<PoolStats>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct PeerDto {
     pub ip: String,
     pub port: u16,
@@ -25,11 +30,41 @@ impl From<&Peer> for PeerDto {
     }
 }
 
+#[derive(Serialize)]
+pub struct PeerDetailsDto {
+    pub ip: String,
+    pub port: u16,
+    pub neighbour: Option<&'static str>,
+    pub rtt_ms: Option<u64>,
+    pub last_seen: Option<u64>,
+    pub failure_streak: u32,
+}
+
+/// Every main chain hash at once, as `/hashes` sends it to peers that
+/// predate paging.
 #[derive(Serialize, Deserialize)]
 pub struct HashesDto {
     pub hashes: Vec<String>,
 }
 
+/// One page of a list endpoint, see `node::page`.
+#[derive(Serialize, Deserialize)]
+pub struct PageDto<T> {
+    pub items: Vec<T>,
+    /// Passed back as `cursor` to get the next page. `None` on the last
+    /// one.
+    pub next_cursor: Option<String>,
+}
+
+impl<T> PageDto<T> {
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> PageDto<U> {
+        PageDto {
+            items: self.items.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct BlockDto {
     pub hash: String,
@@ -63,6 +98,31 @@ impl From<&BlockDto> for Block {
     }
 }
 
+/// A main chain block together with its height.
+#[derive(Serialize)]
+pub struct BlockInfoDto {
+    pub height: usize,
+    #[serde(flatten)]
+    pub block: BlockDto,
+}
+
+#[derive(Serialize)]
+pub struct BlockRefDto {
+    pub hash: String,
+    pub height: usize,
+    pub main_chain: bool,
+}
+
+impl From<BlockRef> for BlockRefDto {
+    fn from(b: BlockRef) -> Self {
+        BlockRefDto {
+            hash: b.hash,
+            height: b.height,
+            main_chain: b.on_main_chain,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TransactionDto {
     pub hash: String,
@@ -90,20 +150,110 @@ impl From<&TransactionDto> for Transaction {
     }
 }
 
+/// Where a transaction stands, as `GET /transactions/{hash}` reports it.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TxState {
+    /// Known, but not in a main chain block yet.
+    Pending,
+    /// In the main chain block given by `block_hash` and `block_height`.
+    Confirmed,
+    /// Not known to the node at all.
+    Unknown,
+    /// Neither a new user nor a transfer, so it never changes a balance.
+    /// Such transactions are still gossiped and mined, and the block fields
+    /// say where one ended up.
+    Malformed,
+}
+
+#[derive(Serialize)]
+pub struct TransactionStatusDto {
+    pub hash: String,
+    pub state: TxState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<TransactionDto>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_height: Option<usize>,
+    pub confirmations: usize,
+}
+
 #[derive(Serialize)]
 pub struct Message<'a> {
     pub message: &'a str,
 }
 
+/// Answer to a client submitting a transaction, so it can track it.
+#[derive(Serialize, Deserialize)]
+pub struct SubmittedDto {
+    pub message: String,
+    pub hash: String,
+    pub timestamp: u64,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UserDto {
     pub name: String,
     pub balance: i64,
 }
 
+/// A page of `GET /users` and the block whose balances it lists.
+#[derive(Serialize)]
+pub struct UsersPageDto {
+    #[serde(flatten)]
+    pub page: PageDto<UserDto>,
+    pub at: BlockRefDto,
+}
+
+/// Where on the main chain a transaction is.
+#[derive(Serialize)]
+pub struct TxRefDto {
+    pub tx_hash: String,
+    pub block_hash: String,
+    pub height: usize,
+}
+
+#[derive(Serialize)]
+pub struct AccountDto {
+    pub name: String,
+    pub balance: i64,
+    /// First transaction creating the user. Users that only ever received
+    /// transfers have none.
+    pub created: Option<TxRefDto>,
+    pub transactions: usize,
+}
+
+#[derive(Serialize)]
+pub struct HistoryEntryDto {
+    #[serde(flatten)]
+    pub location: TxRefDto,
+    pub timestamp: u64,
+    /// `in`, `out` or `self`, seen from the user whose history it is.
+    pub direction: &'static str,
+    pub counterparty: String,
+    pub sum: i64,
+}
+
+#[derive(Serialize)]
+pub struct HistoryPageDto {
+    pub name: String,
+    #[serde(flatten)]
+    pub page: PageDto<HistoryEntryDto>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct TransferDto {
     pub from: String,
     pub to: String,
     pub sum: i64,
 }
+
+/// A transfer included in the main chain.
+#[derive(Serialize)]
+pub struct ChainTransferDto {
+    pub tx_hash: String,
+    pub height: usize,
+    #[serde(flatten)]
+    pub transfer: TransferDto,
+}
@@ -1,70 +1,114 @@
 use crate::http::server::HttpMethod;
+use crate::routes;
 
-#[derive(Debug, Clone)]
-pub enum Route {
-    GetStatus,
-    GetPeers,
-    PostPeers,
-    GetHashes,
-    GetHashesAfter(String),
-    GetBlock(String),
-    PostBlock,
-    GetTransactions,
-    PostTransaction,
-    GetUsers,
-    PostUsers,
-    GetTransfers,
-    PostTransfers,
+routes! {
+    #[derive(Debug, Clone)]
+    pub enum Route {
+        GET "/status" => GetStatus,
+
+        GET "/ping" => GetPing,
+
+        GET "/metrics" => GetMetrics,
+        GET "/events" => GetEvents,
+
+        GET "/peers" => GetPeers,
+        GET "/peers/details" => GetPeerDetails,
+        POST "/peers" => PostPeers,
+
+        GET "/hashes" => GetHashes,
+        GET "/hashes/{hash}" => GetHashesAfter(hash: String),
+        GET "/v2/hashes" => GetHashesPage,
+        GET "/v2/hashes/{hash}" => GetHashesPageAfter(hash: String),
+
+        GET "/blocks" => GetBlocks,
+        GET "/blocks/height/{height}" => GetBlockAtHeight(height: String),
+        GET "/blocks/{hash}" => GetBlock(hash: String),
+        GET "/blocks/{hash}/children" => GetBlockChildren(hash: String),
+        POST "/blocks" => PostBlock,
+
+        GET "/chain/tips" => GetChainTips,
+
+        GET "/transactions" => GetTransactions,
+        GET "/v2/transactions" => GetTransactionsPage,
+        GET "/transactions/{hash}" => GetTransaction(hash: String),
+        POST "/transactions" => PostTransaction,
+
+        GET "/users" => GetUsers,
+        GET "/v2/users" => GetUsersPage,
+        POST "/users" => PostUsers,
+        GET "/users/{name}" => GetUser(name: String),
+        GET "/users/{name}/history" => GetUserHistory(name: String),
+
+        GET "/transfers" => GetTransfers,
+        GET "/v2/transfers" => GetTransfersPage,
+        POST "/transfers" => PostTransfers,
+    }
 }
 
 impl Route {
-    pub fn to_path(&self) -> String {
+    /// Rate limiting class of the route, `None` for routes that are not
+    /// limited. Client submissions are limited far more tightly than the
+    /// gossip nodes send each other.
+    pub fn rate_class(&self) -> Option<&'static str> {
         match self {
-            Route::GetStatus => "/status".into(),
-            Route::GetPeers => "/peers".into(),
-            Route::PostPeers => "/peers".into(),
-            Route::GetHashes => "/hashes".into(),
-            Route::GetHashesAfter(hash) => format!("/hashes/{}", hash),
-            Route::GetBlock(hash) => format!("/blocks/{}", hash),
-            Route::PostBlock => "/blocks".into(),
-            Route::GetTransactions => "/transactions".into(),
-            Route::PostTransaction => "/transactions".into(),
-            Route::GetUsers => "/users".into(),
-            Route::PostUsers => "/users".into(),
-            Route::GetTransfers => "/transfers".into(),
-            Route::PostTransfers => "/transfers".into(),
+            Route::PostUsers | Route::PostTransfers => Some("submit"),
+            Route::PostTransaction | Route::PostBlock => Some("gossip"),
+            Route::PostPeers => Some("peers"),
+            _ => None,
         }
     }
 
-    pub fn parse(method: &HttpMethod) -> Option<Self> {
-        match method {
-            HttpMethod::GET(path) if path == "/status" => Some(Route::GetStatus),
-
-            HttpMethod::GET(path) if path == "/peers" => Some(Route::GetPeers),
-            HttpMethod::POST(path) if path == "/peers" => Some(Route::PostPeers),
+    /// Methods `path` can be requested with, empty for unknown paths.
+    pub fn allowed_methods(path: &str) -> Vec<&'static str> {
+        let mut allowed = Vec::new();
 
-            HttpMethod::GET(path) if path == "/hashes" => Some(Route::GetHashes),
-            HttpMethod::GET(path) if path.starts_with("/hashes/") => path
-                .split('/')
-                .nth(2)
-                .map(|h| Route::GetHashesAfter(h.to_string())),
+        if Route::parse(&HttpMethod::GET(path.to_string())).is_some() {
+            allowed.extend(["GET", "HEAD"]);
+        }
+        if Route::parse(&HttpMethod::POST(path.to_string())).is_some() {
+            allowed.push("POST");
+        }
+        if !allowed.is_empty() {
+            allowed.push("OPTIONS");
+        }
 
-            HttpMethod::GET(path) if path.starts_with("/blocks/") => path
-                .split('/')
-                .nth(2)
-                .map(|h| Route::GetBlock(h.to_string())),
-            HttpMethod::POST(path) if path == "/blocks" => Some(Route::PostBlock),
+        allowed
+    }
+}
 
-            HttpMethod::GET(path) if path == "/transactions" => Some(Route::GetTransactions),
-            HttpMethod::POST(path) if path == "/transactions" => Some(Route::PostTransaction),
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            HttpMethod::GET(path) if path == "/users" => Some(Route::GetUsers),
-            HttpMethod::POST(path) if path == "/users" => Some(Route::PostUsers),
+    #[test]
+    fn routes_parse_their_path_parameters_and_back() {
+        let method = HttpMethod::GET("/users/Ann%20Lee/history".into());
+        let Some(Route::GetUserHistory(name)) = Route::parse(&method) else {
+            panic!("history route not matched");
+        };
+        assert_eq!(name, "Ann Lee");
+        assert_eq!(
+            Route::GetUserHistory(name).to_path(),
+            "/users/Ann%20Lee/history"
+        );
 
-            HttpMethod::GET(path) if path == "/transfers" => Some(Route::GetTransfers),
-            HttpMethod::POST(path) if path == "/transfers" => Some(Route::PostTransfers),
+        // Fixed paths win over the patterns they overlap.
+        let method = HttpMethod::GET("/peers/details".into());
+        assert!(matches!(Route::parse(&method), Some(Route::GetPeerDetails)));
+        let method = HttpMethod::GET("/blocks/height/7".into());
+        assert!(matches!(Route::parse(&method), Some(Route::GetBlockAtHeight(h)) if h == "7"));
+    }
 
-            _ => None,
-        }
+    #[test]
+    fn allowed_methods_list_every_method_of_a_path() {
+        assert_eq!(
+            Route::allowed_methods("/users"),
+            ["GET", "HEAD", "POST", "OPTIONS"]
+        );
+        assert_eq!(
+            Route::allowed_methods("/users/Ann"),
+            ["GET", "HEAD", "OPTIONS"]
+        );
+        assert_eq!(Route::allowed_methods("/nowhere"), Vec::<&str>::new());
     }
 }
@@ -1,53 +1,188 @@
-use crate::http::server::{HttpHandler, HttpRequest, HttpResult};
-use crate::ledger::{self, AddBlockResult, Block, Transaction};
+use crate::events::Event;
+use crate::http::server::{HttpHandler, HttpMethod, HttpRequest, HttpResult, InvalidParam, Query};
+use crate::ledger::{AddBlockResult, Block, Transaction};
+use crate::node::metrics;
+use crate::node::page::{self, Filters, PageRequest};
 use crate::node::protocol::*;
 use crate::node::transactions::{self, ParsedTx};
-use crate::node::{client, route::Route};
-use crate::peers;
-use std::collections::HashMap;
+use crate::node::{Node, client, route::Route};
+use crate::peers::Peer;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+
+pub struct RequestHandler {
+    node: Arc<Node>,
+}
 
-pub struct RequestHandler;
+impl RequestHandler {
+    pub fn new(node: Arc<Node>) -> Self {
+        RequestHandler { node }
+    }
+}
 
 impl HttpHandler for RequestHandler {
     fn handle(&self, req: HttpRequest) -> HttpResult {
-        let HttpRequest { method, body, .. } = req;
+        let node = &self.node;
+        let HttpRequest {
+            method,
+            query,
+            headers,
+            body,
+            ..
+        } = req;
 
         let Some(route) = Route::parse(&method) else {
-            return HttpResult::not_impl();
+            return unrouted(&method);
         };
 
         match route {
-            Route::GetStatus => get_status(),
-            Route::GetPeers => get_peers(),
-            Route::PostPeers => post_peers(&body),
-            Route::GetHashes => get_hashes(),
-            Route::GetHashesAfter(hash) => get_hashes_after(&hash),
-            Route::GetBlock(hash) => get_block(&hash),
-            Route::PostBlock => post_block(&body),
-            Route::GetTransactions => get_transactions(),
-            Route::PostTransaction => post_transaction(&body),
-            Route::GetUsers => get_users(),
-            Route::PostUsers => post_users(&body),
-            Route::GetTransfers => get_transfers(),
-            Route::PostTransfers => post_transfers(&body),
+            Route::GetStatus => get_status(node),
+            Route::GetPing => get_ping(),
+            Route::GetMetrics => get_metrics(node),
+            Route::GetEvents => get_events(node, &query),
+            Route::GetPeers => get_peers(node),
+            Route::GetPeerDetails => get_peer_details(node),
+            Route::PostPeers => post_peers(node, &body),
+            Route::GetHashes => get_hashes(node),
+            Route::GetHashesAfter(hash) => get_hashes_after(node, &hash),
+            Route::GetHashesPage => get_hashes_page(node, &query),
+            Route::GetHashesPageAfter(hash) => get_hashes_page_after(node, &hash, &query),
+            Route::GetBlocks => get_blocks(node, &query),
+            Route::GetBlockAtHeight(height) => get_block_at_height(node, &height),
+            Route::GetBlock(hash) => get_block(node, &hash, headers.get("if-none-match")),
+            Route::GetBlockChildren(hash) => get_block_children(node, &hash),
+            Route::PostBlock => post_block(node, &body),
+            Route::GetChainTips => get_chain_tips(node),
+            Route::GetTransactions => get_transactions(node),
+            Route::GetTransactionsPage => get_transactions_page(node, &query),
+            Route::GetTransaction(hash) => get_transaction(node, &hash),
+            Route::PostTransaction => post_transaction(node, &body),
+            Route::GetUsers => get_users(node),
+            Route::GetUsersPage => get_users_page(node, &query),
+            Route::PostUsers => post_users(node, &body),
+            Route::GetUser(name) => get_user(node, &name),
+            Route::GetUserHistory(name) => get_user_history(node, &name, &query),
+            Route::GetTransfers => get_transfers(node),
+            Route::GetTransfersPage => get_transfers_page(node, &query),
+            Route::PostTransfers => post_transfers(node, &body),
         }
     }
 }
 
-fn get_status() -> HttpResult {
+/// Answers requests no route matched: `OPTIONS` and wrong methods on known
+/// paths get the list of allowed methods.
+fn unrouted(method: &HttpMethod) -> HttpResult {
+    let allowed = Route::allowed_methods(method.path());
+
+    if allowed.is_empty() {
+        HttpResult::not_impl()
+    } else if let HttpMethod::OPTIONS(_) = method {
+        HttpResult::options(&allowed)
+    } else {
+        HttpResult::method_not_allowed(&allowed)
+    }
+}
+
+fn get_status(node: &Node) -> HttpResult {
     HttpResult::ok(&StatusDto {
-        block_height: ledger::chain_len(),
-        last_block_hash: ledger::last_block_hash(),
-        pending_txs_num: ledger::pending_txs_len(),
-        known_peers: peers::get_known_peers()
+        block_height: node.ledger().chain_len(),
+        last_block_hash: node.ledger().last_block_hash(),
+        pending_txs_num: node.ledger().pending_txs_len(),
+        known_peers: node
+            .peers()
+            .get_known_peers()
+            .iter()
+            .map(PeerDto::from)
+            .collect(),
+        outbound_peers: node
+            .peers()
+            .outbound_neighbours()
             .iter()
-            .map(|p| PeerDto::from(p))
+            .map(PeerDto::from)
             .collect(),
+        inbound_peers: node
+            .peers()
+            .inbound_neighbours()
+            .iter()
+            .map(PeerDto::from)
+            .collect(),
+        http_pool: node.http_pool_stats(),
     })
 }
 
-fn get_peers() -> HttpResult {
-    let peers = peers::select_random_peers();
+fn get_ping() -> HttpResult {
+    HttpResult::ok(&Message { message: "pong" })
+}
+
+fn get_metrics(node: &Node) -> HttpResult {
+    HttpResult::text(200, "text/plain; version=0.0.4", metrics::render(node))
+}
+
+/// How long an event stream stays silent before sending a comment, so
+/// proxies keep it open and a client that went away is noticed.
+const EVENTS_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Streams the node's events as Server-Sent Events until the client goes
+/// away or the node shuts down. `types`, e.g. `block,reorg`, limits which
+/// ones are sent.
+fn get_events(node: &Arc<Node>, query: &Query) -> HttpResult {
+    let wanted: Option<Vec<&'static str>> = match query.get("types") {
+        Some(types) => {
+            let wanted: Option<Vec<_>> = types
+                .split(',')
+                .map(|t| Event::NAMES.into_iter().find(|name| *name == t))
+                .collect();
+            match wanted {
+                Some(wanted) => Some(wanted),
+                None => return invalid_param(InvalidParam("types".into())),
+            }
+        }
+        None => None,
+    };
+
+    let mut events = node.events().subscribe();
+    let (tx, rx) = mpsc::channel(16);
+    let node = Arc::clone(node);
+
+    node.runtime().clone().spawn(async move {
+        loop {
+            let piece = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) if wanted.as_ref().is_some_and(|w| !w.contains(&event.name())) => {
+                        continue;
+                    }
+                    Ok(event) => format!(
+                        "event: {}\ndata: {}\n\n",
+                        event.name(),
+                        serde_json::to_string(&event).unwrap_or_default()
+                    ),
+                    Err(RecvError::Lagged(missed)) => format!(": missed {} events\n\n", missed),
+                    Err(RecvError::Closed) => return,
+                },
+                running = node.idle(EVENTS_KEEPALIVE) => match running {
+                    true => ": keepalive\n\n".to_string(),
+                    false => return,
+                },
+            };
+
+            // The client went away.
+            if tx.send(piece).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    HttpResult {
+        content_type: "text/event-stream",
+        ..HttpResult::stream(200, rx)
+    }
+    .with_header("Cache-Control", "no-cache")
+}
+
+fn get_peers(node: &Node) -> HttpResult {
+    let peers = node.peers().select_random_peers();
 
     let peer_list: Vec<PeerDto> = peers
         .into_iter()
@@ -60,7 +195,38 @@ fn get_peers() -> HttpResult {
     HttpResult::ok(&peer_list)
 }
 
-fn post_peers(body: &str) -> HttpResult {
+fn get_peer_details(node: &Node) -> HttpResult {
+    let outbound = node.peers().outbound_neighbours();
+    let inbound = node.peers().inbound_neighbours();
+
+    let details: Vec<PeerDetailsDto> = node
+        .peers()
+        .get_peer_details()
+        .into_iter()
+        .map(|(peer, stats)| {
+            let neighbour = if outbound.contains(&peer) {
+                Some("outbound")
+            } else if inbound.contains(&peer) {
+                Some("inbound")
+            } else {
+                None
+            };
+
+            PeerDetailsDto {
+                ip: peer.ip,
+                port: peer.port,
+                neighbour,
+                rtt_ms: stats.rtt_ms,
+                last_seen: stats.last_seen,
+                failure_streak: stats.failure_streak,
+            }
+        })
+        .collect();
+
+    HttpResult::ok(&details)
+}
+
+fn post_peers(node: &Node, body: &str) -> HttpResult {
     let dto: PeerDto = match serde_json::from_str(body) {
         Ok(v) => v,
         Err(_) => {
@@ -68,32 +234,265 @@ fn post_peers(body: &str) -> HttpResult {
         }
     };
 
-    peers::add_peer(dto.ip, dto.port);
+    node.peers().add_peer(dto.ip.clone(), dto.port);
+    node.peers().accept_inbound(Peer::new(dto.ip, dto.port));
     HttpResult::ok(&Message {
         message: "Advertisement received",
     })
 }
 
-fn get_hashes() -> HttpResult {
-    let hashes = ledger::get_all_block_hashes();
-    HttpResult::ok(&HashesDto { hashes })
+/// Hashes returned by one `/v2/hashes` page unless `limit` asks for fewer.
+const MAX_HASHES_PER_PAGE: usize = 2000;
+
+// `/hashes` and `/hashes/{hash}` keep sending the whole chain at once, as
+// peers that predate paging expect; the paged versions live under `/v2`.
+
+fn get_hashes(node: &Arc<Node>) -> HttpResult {
+    stream_hashes(node, 1, usize::MAX, HashesBody::Legacy)
 }
 
-fn get_hashes_after(start_hash: &str) -> HttpResult {
+fn get_hashes_after(node: &Arc<Node>, start_hash: &str) -> HttpResult {
     //TODO: Should probably send bad_req when there is no such hash
-    let hashes = ledger::get_block_hashes_after(start_hash);
-    HttpResult::ok(&HashesDto { hashes })
+    match node.ledger().main_chain_height(start_hash) {
+        Some(height) => stream_hashes(node, height + 1, usize::MAX, HashesBody::Legacy),
+        None => HttpResult::ok(&HashesDto { hashes: Vec::new() }),
+    }
+}
+
+fn get_hashes_page(node: &Arc<Node>, query: &Query) -> HttpResult {
+    hashes_page(node, query, 1)
+}
+
+fn get_hashes_page_after(node: &Arc<Node>, start_hash: &str, query: &Query) -> HttpResult {
+    match node.ledger().main_chain_height(start_hash) {
+        Some(height) => hashes_page(node, query, height + 1),
+        None => HttpResult::ok(&PageDto::<String> {
+            items: Vec::new(),
+            next_cursor: None,
+        }),
+    }
 }
 
-fn get_block(hash: &str) -> HttpResult {
-    match ledger::get_block(hash) {
+/// Page of main chain hashes from height `first` on, narrowed by the
+/// cursor, `min_height` and `max_height`. The cursor is the height of the
+/// last hash sent.
+fn hashes_page(node: &Arc<Node>, query: &Query, first: usize) -> HttpResult {
+    let (page, filters) = match list_params(query, MAX_HASHES_PER_PAGE) {
+        Ok(params) => params,
+        Err(e) => return invalid_param(e),
+    };
+    let after = match page.after_as::<usize>() {
+        Ok(after) => after.unwrap_or(0),
+        Err(e) => return invalid_param(e),
+    };
+    // No hash follows the last height there is.
+    let Some(next) = after.checked_add(1) else {
+        return invalid_param(InvalidParam("cursor".into()));
+    };
+
+    let start = first.max(next).max(filters.min_height.unwrap_or(0));
+    let end = filters.max_height.unwrap_or(usize::MAX);
+    let limit = page.limit.min(end.saturating_add(1).saturating_sub(start));
+
+    stream_hashes(node, start, limit, HashesBody::Page { end })
+}
+
+/// Main chain hashes sent per chunk of a streamed hashes response.
+const HASHES_PER_CHUNK: usize = 500;
+
+/// Shape of a streamed hashes response.
+enum HashesBody {
+    /// `HashesDto`.
+    Legacy,
+    /// `PageDto`, whose cursor only points on while the height after the
+    /// page is at most `end`.
+    Page { end: usize },
+}
+
+/// Streams up to `limit` main chain hashes from height `start` on, reading
+/// the chain a chunk at a time so a long response is never serialised at
+/// once. The response ends early at the tip.
+fn stream_hashes(node: &Arc<Node>, start: usize, limit: usize, body: HashesBody) -> HttpResult {
+    let (tx, rx) = mpsc::channel(4);
+    let node = Arc::clone(node);
+
+    node.runtime().clone().spawn(async move {
+        let mut height = start;
+        let mut sent = 0;
+        let mut first = true;
+        let mut piece = match body {
+            HashesBody::Legacy => String::from("{\"hashes\":["),
+            HashesBody::Page { .. } => String::from("{\"items\":["),
+        };
+
+        loop {
+            let wanted = HASHES_PER_CHUNK.min(limit - sent);
+            let hashes = node.ledger().get_block_hashes_range(height, wanted);
+            height += hashes.len();
+            sent += hashes.len();
+
+            for hash in &hashes {
+                if !first {
+                    piece.push(',');
+                }
+                first = false;
+                piece.push_str(&format!("\"{}\"", hash));
+            }
+
+            if hashes.len() < wanted || sent == limit {
+                match body {
+                    HashesBody::Legacy => piece.push_str("]}"),
+                    HashesBody::Page { end } => {
+                        let more = height <= end
+                            && !node.ledger().get_block_hashes_range(height, 1).is_empty();
+                        let next_cursor = match more {
+                            true => format!("\"{}\"", page::encode(&(height - 1).to_string())),
+                            false => "null".to_string(),
+                        };
+                        piece.push_str(&format!("],\"next_cursor\":{}}}", next_cursor));
+                    }
+                }
+
+                let _ = tx.send(piece).await;
+                return;
+            }
+
+            // The client went away.
+            if tx.send(std::mem::take(&mut piece)).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    HttpResult::stream(200, rx)
+}
+
+fn get_block(node: &Node, hash: &str, if_none_match: Option<&String>) -> HttpResult {
+    // A block never changes once stored, so its hash is a perfect ETag.
+    let etag = format!("\"{}\"", hash);
+
+    let result = match node.ledger().get_block(hash) {
+        Some(_) if if_none_match == Some(&etag) => HttpResult::empty(304),
         Some(block) => HttpResult::ok(&BlockDto::from(&block)),
+        None => return HttpResult::not_found(),
+    };
+
+    result
+        .with_header("ETag", etag)
+        .with_header("Cache-Control", "public, max-age=31536000, immutable")
+}
+
+fn invalid_param(e: InvalidParam) -> HttpResult {
+    HttpResult::err(400, &format!("Invalid parameter: {}", e.0))
+}
+
+/// Cursor, limit and filters of a list endpoint's request.
+fn list_params(query: &Query, max: usize) -> Result<(PageRequest, Filters), InvalidParam> {
+    Ok((
+        PageRequest::from_query(query, max)?,
+        Filters::from_query(query)?,
+    ))
+}
+
+/// Items returned by one page of `/users`, `/transfers` and
+/// `/transactions` unless `limit` asks for fewer.
+const MAX_ITEMS_PER_PAGE: usize = 100;
+
+/// Blocks returned by one `GET /blocks` page unless `limit` asks for fewer.
+const MAX_BLOCKS_PER_PAGE: usize = 100;
+
+/// Main chain blocks from height `start` to `end`, both included, a page
+/// at a time. The cursor is the height of the last block sent.
+fn get_blocks(node: &Node, query: &Query) -> HttpResult {
+    let (start, end) = match block_range(query) {
+        Ok(range) => range,
+        Err(e) => return invalid_param(e),
+    };
+    let page = match PageRequest::from_query(query, MAX_BLOCKS_PER_PAGE) {
+        Ok(page) => page,
+        Err(e) => return invalid_param(e),
+    };
+    let first = match page
+        .after_as::<usize>()
+        .map(|after| after.map(|h| h.checked_add(1)))
+    {
+        Ok(None) => start,
+        Ok(Some(Some(next))) => next.max(start),
+        Ok(Some(None)) | Err(_) => return invalid_param(InvalidParam("cursor".into())),
+    };
+
+    // One extra block tells `page` whether another page follows.
+    let blocks = node
+        .ledger()
+        .get_blocks_range(first, page.limit + 1)
+        .into_iter()
+        .take_while(|(height, _)| *height <= end)
+        .map(|(height, block)| BlockInfoDto {
+            height,
+            block: BlockDto::from(&block),
+        });
+
+    HttpResult::ok(&page.page(blocks, |block| block.height.to_string()))
+}
+
+/// `start` and `end` of a `GET /blocks` query, with defaults.
+fn block_range(query: &Query) -> Result<(usize, usize), InvalidParam> {
+    let start = query.parse_param::<usize>("start")?.unwrap_or(1);
+    let end = query.parse_param::<usize>("end")?.unwrap_or(usize::MAX);
+
+    if start == 0 {
+        return Err(InvalidParam("start".into()));
+    }
+    if end < start {
+        return Err(InvalidParam("end".into()));
+    }
+
+    Ok((start, end))
+}
+
+fn get_block_at_height(node: &Node, height: &str) -> HttpResult {
+    let Ok(height) = height.parse::<usize>() else {
+        return invalid_param(InvalidParam("height".into()));
+    };
+
+    match node.ledger().get_block_at_height(height) {
+        Some(block) => HttpResult::ok(&BlockInfoDto {
+            height,
+            block: BlockDto::from(&block),
+        }),
         None => HttpResult::not_found(),
     }
 }
 
-fn get_transactions() -> HttpResult {
-    let txs: Vec<TransactionDto> = ledger::get_pending_transactions()
+fn get_block_children(node: &Node, hash: &str) -> HttpResult {
+    match node.ledger().get_children(hash) {
+        Some(children) => HttpResult::ok(
+            &children
+                .into_iter()
+                .map(BlockRefDto::from)
+                .collect::<Vec<_>>(),
+        ),
+        None => HttpResult::not_found(),
+    }
+}
+
+fn get_chain_tips(node: &Node) -> HttpResult {
+    let tips: Vec<BlockRefDto> = node
+        .ledger()
+        .chain_tips()
+        .into_iter()
+        .map(BlockRefDto::from)
+        .collect();
+
+    HttpResult::ok(&tips)
+}
+
+/// Every pending transaction at once, as `/transactions` sends them to
+/// peers that predate paging.
+fn get_transactions(node: &Node) -> HttpResult {
+    let txs: Vec<TransactionDto> = node
+        .ledger()
+        .get_pending_transactions()
         .iter()
         .map(TransactionDto::from)
         .collect();
@@ -101,7 +500,74 @@ fn get_transactions() -> HttpResult {
     HttpResult::ok(&txs)
 }
 
-fn post_transaction(body: &str) -> HttpResult {
+/// Pending transactions ordered by timestamp, then hash, which the cursor
+/// holds as `timestamp:hash`.
+fn get_transactions_page(node: &Node, query: &Query) -> HttpResult {
+    let (page, filters) = match list_params(query, MAX_ITEMS_PER_PAGE) {
+        Ok(params) => params,
+        Err(e) => return invalid_param(e),
+    };
+    let after = match page.after.as_deref().map(parse_tx_key) {
+        Some(Some(key)) => Some(key),
+        Some(None) => return invalid_param(InvalidParam("cursor".into())),
+        None => None,
+    };
+
+    let pending = node.ledger().get_pending_transactions();
+    let txs = pending
+        .iter()
+        .filter(|tx| {
+            after
+                .as_ref()
+                .is_none_or(|(ts, hash)| (tx.timestamp, &tx.hash) > (*ts, hash))
+        })
+        .filter(|tx| filters.transaction(transactions::parse_transaction(&tx.data).as_ref()))
+        .map(TransactionDto::from);
+
+    HttpResult::ok(&page.page(txs, |tx| format!("{}:{}", tx.timestamp, tx.hash)))
+}
+
+fn parse_tx_key(key: &str) -> Option<(u64, String)> {
+    let (timestamp, hash) = key.split_once(':')?;
+    Some((timestamp.parse().ok()?, hash.to_string()))
+}
+
+fn get_transaction(node: &Node, hash: &str) -> HttpResult {
+    let Some(lookup) = node.ledger().find_transaction(hash) else {
+        return HttpResult::json(
+            404,
+            &TransactionStatusDto {
+                hash: hash.to_string(),
+                state: TxState::Unknown,
+                transaction: None,
+                block_hash: None,
+                block_height: None,
+                confirmations: 0,
+            },
+        );
+    };
+
+    let state = if !transactions::is_valid(&lookup.transaction) {
+        TxState::Malformed
+    } else if lookup.block.is_some() {
+        TxState::Confirmed
+    } else {
+        TxState::Pending
+    };
+    let confirmations = lookup.confirmations();
+    let (block_hash, block_height) = lookup.block.unzip();
+
+    HttpResult::ok(&TransactionStatusDto {
+        hash: hash.to_string(),
+        state,
+        transaction: Some(TransactionDto::from(&lookup.transaction)),
+        block_hash,
+        block_height,
+        confirmations,
+    })
+}
+
+fn post_transaction(node: &Arc<Node>, body: &str) -> HttpResult {
     let dto: TransactionDto = match serde_json::from_str(body) {
         Ok(v) => v,
         Err(_) => {
@@ -109,8 +575,8 @@ fn post_transaction(body: &str) -> HttpResult {
         }
     };
 
-    if ledger::add_transaction(&Transaction::from(&dto)) {
-        client::broadcast_transaction(dto);
+    if node.ledger().add_transaction(&Transaction::from(&dto)) {
+        client::broadcast_transaction(node, dto);
         HttpResult::created(&Message {
             message: "Transaction accepted",
         })
@@ -121,7 +587,7 @@ fn post_transaction(body: &str) -> HttpResult {
     }
 }
 
-fn post_block(body: &str) -> HttpResult {
+fn post_block(node: &Arc<Node>, body: &str) -> HttpResult {
     let dto: BlockDto = match serde_json::from_str(body) {
         Ok(v) => v,
         Err(_) => {
@@ -129,9 +595,9 @@ fn post_block(body: &str) -> HttpResult {
         }
     };
 
-    match ledger::add_block(&Block::from(&dto)) {
+    match node.ledger().add_block(&Block::from(&dto)) {
         AddBlockResult::Added => {
-            client::broadcast_block(dto);
+            client::broadcast_block(node, dto);
             HttpResult::created(&Message {
                 message: "Block accepted",
             })
@@ -146,35 +612,170 @@ fn post_block(body: &str) -> HttpResult {
     }
 }
 
-fn get_users() -> HttpResult {
-    let users = ledger::with_blocks(|blocks| {
-        let mut balances: HashMap<String, i64> = HashMap::new();
+// `/users` and `/transfers` keep sending everything at once, as scripts
+// written before paging expect; the paged versions live under `/v2`.
 
-        for block in blocks {
-            for tx in &block.transactions {
-                match transactions::parse_transaction(&tx.data) {
-                    Some(ParsedTx::CreateUser { name, balance }) => {
-                        balances.insert(name, balance);
-                    }
-                    Some(ParsedTx::Transfer { from, to, sum }) => {
-                        *balances.entry(from).or_insert(0) -= sum;
-                        *balances.entry(to).or_insert(0) += sum;
-                    }
-                    None => {}
+/// Every user with their balance after the tip, ordered by name.
+fn get_users(node: &Node) -> HttpResult {
+    let Some((_, balances)) = node.ledger().balances_at(&node.ledger().last_block_hash()) else {
+        return HttpResult::not_found();
+    };
+
+    let mut users: Vec<UserDto> = balances
+        .into_iter()
+        .map(|(name, balance)| UserDto { name, balance })
+        .collect();
+    users.sort_by(|a, b| a.name.cmp(&b.name));
+
+    HttpResult::ok(&users)
+}
+
+/// Users ordered by name, which is also the cursor. `min_amount` and
+/// `max_amount` bound the balance. Balances are those after the main chain
+/// block at `at_height`, after block `at_block` or after the tip.
+fn get_users_page(node: &Node, query: &Query) -> HttpResult {
+    let (page, filters) = match list_params(query, MAX_ITEMS_PER_PAGE) {
+        Ok(params) => params,
+        Err(e) => return invalid_param(e),
+    };
+
+    let at_block = match (
+        query.parse_param::<usize>("at_height"),
+        query.get("at_block"),
+    ) {
+        (Err(e), _) => return invalid_param(e),
+        (Ok(Some(_)), Some(_)) => return invalid_param(InvalidParam("at_block".into())),
+        (Ok(Some(height)), None) => match node.ledger().get_block_at_height(height) {
+            Some(block) => block.hash,
+            None => return HttpResult::not_found(),
+        },
+        (Ok(None), Some(hash)) => hash.to_string(),
+        (Ok(None), None) => node.ledger().last_block_hash(),
+    };
+
+    let Some((at, balances)) = node.ledger().balances_at(&at_block) else {
+        return HttpResult::not_found();
+    };
+
+    let mut balances: Vec<(String, i64)> = balances.into_iter().collect();
+    balances.sort();
+
+    let users = balances
+        .into_iter()
+        .filter(|(name, _)| page.after.as_ref().is_none_or(|after| name > after))
+        .filter(|(_, balance)| filters.amount(*balance))
+        .map(|(name, balance)| UserDto { name, balance });
+
+    HttpResult::ok(&UsersPageDto {
+        page: page.page(users, |user| user.name.clone()),
+        at: at.into(),
+    })
+}
+
+fn get_user(node: &Node, name: &str) -> HttpResult {
+    let txs = node.ledger().get_address_transactions(name);
+    if txs.is_empty() {
+        return HttpResult::not_found();
+    }
+
+    let mut balance = 0;
+    let mut created = None;
+
+    for tx in &txs {
+        match transactions::parse_transaction(&tx.transaction.data) {
+            Some(ParsedTx::CreateUser {
+                balance: initial, ..
+            }) => {
+                balance = initial;
+                created.get_or_insert_with(|| TxRefDto {
+                    tx_hash: tx.transaction.hash.clone(),
+                    block_hash: tx.block_hash.clone(),
+                    height: tx.height,
+                });
+            }
+            Some(ParsedTx::Transfer { from, to, sum }) => {
+                if from == name {
+                    balance -= sum;
+                }
+                if to == name {
+                    balance += sum;
                 }
             }
+            None => {}
         }
+    }
+
+    HttpResult::ok(&AccountDto {
+        name: name.to_string(),
+        balance,
+        created,
+        transactions: txs.len(),
+    })
+}
+
+/// Transfers from and to user `name`, newest first. The cursor is the
+/// height and block position of the last transfer sent, `height:index`,
+/// so blocks mined in between don't shift the following pages.
+fn get_user_history(node: &Node, name: &str, query: &Query) -> HttpResult {
+    let page = match PageRequest::from_query(query, MAX_ITEMS_PER_PAGE) {
+        Ok(page) => page,
+        Err(e) => return invalid_param(e),
+    };
+    let before = match page.after.as_deref().map(parse_position_key) {
+        Some(Some(key)) => Some(key),
+        Some(None) => return invalid_param(InvalidParam("cursor".into())),
+        None => None,
+    };
+
+    let txs = node.ledger().get_address_transactions(name);
+    if txs.is_empty() {
+        return HttpResult::not_found();
+    }
 
-        balances
-            .into_iter()
-            .map(|(name, balance)| UserDto { name, balance })
-            .collect::<Vec<_>>()
+    let transfers = txs
+        .into_iter()
+        .rev()
+        .filter(|tx| before.is_none_or(|before| (tx.height, tx.index) < before))
+        .filter_map(|tx| {
+            let Some(ParsedTx::Transfer { from, to, sum }) =
+                transactions::parse_transaction(&tx.transaction.data)
+            else {
+                return None;
+            };
+
+            let (direction, counterparty) = if from == to {
+                ("self", to)
+            } else if from == name {
+                ("out", to)
+            } else {
+                ("in", from)
+            };
+
+            let entry = HistoryEntryDto {
+                location: TxRefDto {
+                    tx_hash: tx.transaction.hash,
+                    block_hash: tx.block_hash,
+                    height: tx.height,
+                },
+                timestamp: tx.transaction.timestamp,
+                direction,
+                counterparty,
+                sum,
+            };
+            Some((tx.index, entry))
+        });
+
+    let page = page.page(transfers, |(index, entry)| {
+        format!("{}:{}", entry.location.height, index)
     });
 
-    HttpResult::ok(&users)
+    HttpResult::ok(&HistoryPageDto {
+        name: name.to_string(),
+        page: page.map(|(_, entry)| entry),
+    })
 }
 
-fn post_users(body: &str) -> HttpResult {
+fn post_users(node: &Arc<Node>, body: &str) -> HttpResult {
     let dto: UserDto = match serde_json::from_str(body) {
         Ok(v) => v,
         Err(_) => {
@@ -182,38 +783,127 @@ fn post_users(body: &str) -> HttpResult {
         }
     };
 
+    if user_exists(node, &dto.name) {
+        return HttpResult::err(409, "User already exists");
+    }
 
     let data = format!("{}={}", dto.name, dto.balance);
-    let tx = Transaction::from_data(data);
-    ledger::add_transaction(&tx);
-    client::broadcast_transaction(TransactionDto::from(&tx));
+    submit(node, data, "User added")
+}
 
-    HttpResult::created(&Message {
-        message: "User added",
-    })
+/// Whether `name` has a balance on the main chain or a pending transaction
+/// creating it.
+fn user_exists(node: &Node, name: &str) -> bool {
+    node.ledger().has_address(name)
+        || node.ledger().get_pending_transactions().iter().any(|tx| {
+            matches!(
+                transactions::parse_transaction(&tx.data),
+                Some(ParsedTx::CreateUser { name: pending, .. }) if pending == name
+            )
+        })
 }
 
-fn get_transfers() -> HttpResult {
-    let transfers = ledger::with_blocks(|blocks| {
-        let mut transfers = Vec::new();
+/// Adds a client's transaction with `data` and gossips it, answering with
+/// where it can be tracked. The same data sent twice within a second hashes
+/// the same, so the second time the existing transaction is returned with
+/// `200` instead.
+fn submit(node: &Arc<Node>, data: String, message: &str) -> HttpResult {
+    let tx = Transaction::new(data, node.clock().now());
+    let location = Route::GetTransaction(tx.hash.clone()).to_path();
+
+    if !node.ledger().add_transaction(&tx) {
+        return HttpResult::ok(&SubmittedDto {
+            message: "Transaction already exists".to_string(),
+            hash: tx.hash,
+            timestamp: tx.timestamp,
+        })
+        .with_header("Location", location);
+    }
 
-        for block in blocks {
-            for tx in &block.transactions {
-                if let Some(ParsedTx::Transfer { from, to, sum }) =
-                    transactions::parse_transaction(&tx.data)
-                {
-                    transfers.push(TransferDto { from, to, sum });
-                }
-            }
-        }
+    client::broadcast_transaction(node, TransactionDto::from(&tx));
 
-        transfers
+    HttpResult::created(&SubmittedDto {
+        message: message.to_string(),
+        hash: tx.hash,
+        timestamp: tx.timestamp,
+    })
+    .with_header("Location", location)
+}
+
+/// Every main chain transfer, oldest first.
+fn get_transfers(node: &Node) -> HttpResult {
+    let transfers: Vec<TransferDto> = node.ledger().with_blocks(|blocks| {
+        blocks
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .filter_map(|tx| match transactions::parse_transaction(&tx.data) {
+                Some(ParsedTx::Transfer { from, to, sum }) => Some(TransferDto { from, to, sum }),
+                _ => None,
+            })
+            .collect()
     });
 
     HttpResult::ok(&transfers)
 }
 
-fn post_transfers(body: &str) -> HttpResult {
+/// Main chain transfers, oldest first. The cursor is the height of the
+/// last transfer's block and its position within it, `height:index`.
+fn get_transfers_page(node: &Node, query: &Query) -> HttpResult {
+    let (page, filters) = match list_params(query, MAX_ITEMS_PER_PAGE) {
+        Ok(params) => params,
+        Err(e) => return invalid_param(e),
+    };
+    let after = match page.after.as_deref().map(parse_position_key) {
+        Some(Some(key)) => Some(key),
+        Some(None) => return invalid_param(InvalidParam("cursor".into())),
+        None => None,
+    };
+
+    let transfers = node.ledger().with_blocks(|blocks| {
+        let first = after.map_or(0, |(height, _)| height.saturating_sub(1));
+
+        let transfers = blocks
+            .iter()
+            .zip(1..)
+            .skip(first)
+            .filter(|(_, height)| filters.height(*height))
+            .flat_map(|(block, height)| {
+                block
+                    .transactions
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, tx)| (height, i, tx))
+            })
+            .filter(|(height, i, _)| after.is_none_or(|after| (*height, *i) > after))
+            .filter_map(|(height, i, tx)| {
+                let parsed = transactions::parse_transaction(&tx.data);
+                if !filters.transaction(parsed.as_ref()) {
+                    return None;
+                }
+                let Some(ParsedTx::Transfer { from, to, sum }) = parsed else {
+                    return None;
+                };
+
+                let dto = ChainTransferDto {
+                    tx_hash: tx.hash.clone(),
+                    height,
+                    transfer: TransferDto { from, to, sum },
+                };
+                Some((i, dto))
+            });
+
+        page.page(transfers, |(i, dto)| format!("{}:{}", dto.height, i))
+    });
+
+    HttpResult::ok(&transfers.map(|(_, dto)| dto))
+}
+
+fn parse_position_key(key: &str) -> Option<(usize, usize)> {
+    let (height, index) = key.split_once(':')?;
+    Some((height.parse().ok()?, index.parse().ok()?))
+}
+
+fn post_transfers(node: &Arc<Node>, body: &str) -> HttpResult {
     let dto: TransferDto = match serde_json::from_str(body) {
         Ok(v) => v,
         Err(_) => {
@@ -222,11 +912,23 @@ fn post_transfers(body: &str) -> HttpResult {
     };
 
     let data = format!("{}->{}:{}", dto.from, dto.to, dto.sum);
-    let tx = Transaction::from_data(data);
-    ledger::add_transaction(&tx);
-    client::broadcast_transaction(TransactionDto::from(&tx));
+    submit(node, data, "Transfer accepted")
+}
 
-    HttpResult::created(&Message {
-        message: "Transfer accepted",
-    })
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrong_methods_on_known_paths_get_405_with_allow() {
+        let result = unrouted(&HttpMethod::DELETE("/transactions".into()));
+        assert_eq!(result.status, 405);
+        assert_eq!(result.headers["Allow"], "GET, HEAD, POST, OPTIONS");
+
+        let result = unrouted(&HttpMethod::OPTIONS("/status".into()));
+        assert_eq!(result.status, 204);
+        assert_eq!(result.headers["Allow"], "GET, HEAD, OPTIONS");
+
+        assert_eq!(unrouted(&HttpMethod::GET("/nowhere".into())).status, 501);
+    }
 }
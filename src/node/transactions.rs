@@ -1,9 +1,15 @@
+use crate::ledger::{Block, Transaction};
+use std::collections::HashMap;
+
 #[derive(Debug, Clone)]
 pub enum ParsedTx {
     CreateUser { name: String, balance: i64 },
     Transfer { from: String, to: String, sum: i64 },
 }
 
+// Let chains would collapse these, but the Docker image's toolchain
+// predates them.
+#[allow(clippy::collapsible_if)]
 pub fn parse_transaction(data: &str) -> Option<ParsedTx> {
     if let Some((name, balance)) = data.split_once('=') {
         if let Ok(amount) = balance.parse::<i64>() {
@@ -28,3 +34,37 @@ pub fn parse_transaction(data: &str) -> Option<ParsedTx> {
 
     None
 }
+
+/// Whether `tx` can ever take effect, i.e. its data is a user creation or
+/// a transfer. Others are still gossiped and mined, they just never change
+/// a balance.
+pub fn is_valid(tx: &Transaction) -> bool {
+    parse_transaction(&tx.data).is_some()
+}
+
+/// Folds every transaction in `blocks` into per-user balances.
+pub fn compute_balances(blocks: &[Block]) -> HashMap<String, i64> {
+    let mut balances: HashMap<String, i64> = HashMap::new();
+
+    for block in blocks {
+        apply_block(&mut balances, block);
+    }
+
+    balances
+}
+
+/// Folds the transactions of `block` into `balances`.
+pub fn apply_block(balances: &mut HashMap<String, i64>, block: &Block) {
+    for tx in &block.transactions {
+        match parse_transaction(&tx.data) {
+            Some(ParsedTx::CreateUser { name, balance }) => {
+                balances.insert(name, balance);
+            }
+            Some(ParsedTx::Transfer { from, to, sum }) => {
+                *balances.entry(from).or_insert(0) -= sum;
+                *balances.entry(to).or_insert(0) += sum;
+            }
+            None => {}
+        }
+    }
+}
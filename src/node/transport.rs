@@ -0,0 +1,170 @@
+use crate::http::server::percent_encode;
+use crate::node::route::Route;
+use crate::peers::Peer;
+use reqwest::Client;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+}
+
+impl Method {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+        }
+    }
+}
+
+/// A request one node sends to another.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    /// Pattern of the route `path` was filled from, see `Route::pattern`.
+    pub route: &'static str,
+    pub body: Option<String>,
+    pub timeout: Option<Duration>,
+}
+
+impl Request {
+    pub fn get(route: &Route) -> Self {
+        Request {
+            method: Method::Get,
+            path: route.to_path(),
+            route: route.pattern(),
+            body: None,
+            timeout: None,
+        }
+    }
+
+    pub fn post_json<T: Serialize>(route: &Route, value: &T) -> Option<Self> {
+        let body = serde_json::to_string(value).ok()?;
+
+        Some(Request {
+            method: Method::Post,
+            path: route.to_path(),
+            route: route.pattern(),
+            body: Some(body),
+            timeout: None,
+        })
+    }
+
+    /// Appends `key=value` to the query string.
+    pub fn param(mut self, key: &str, value: &str) -> Self {
+        let separator = if self.path.contains('?') { '&' } else { '?' };
+        self.path = format!(
+            "{}{}{}={}",
+            self.path,
+            separator,
+            key,
+            percent_encode(value)
+        );
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Option<T> {
+        serde_json::from_str(&self.body).ok()
+    }
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    Unreachable,
+    Timeout,
+    Other(String),
+}
+
+pub type ResponseFuture = Pin<Box<dyn Future<Output = Result<Response, TransportError>> + Send>>;
+
+/// How a node reaches its peers. Real nodes talk HTTP, the simulation
+/// harness delivers requests in-process.
+pub trait Transport: Send + Sync + 'static {
+    fn send(&self, from: &Peer, to: &Peer, request: Request) -> ResponseFuture;
+}
+
+pub struct HttpTransport {
+    client: Client,
+}
+
+impl HttpTransport {
+    pub fn new() -> Self {
+        let client = Client::builder()
+            .pool_max_idle_per_host(256)
+            .build()
+            .unwrap();
+
+        HttpTransport { client }
+    }
+}
+
+impl Default for HttpTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&self, _from: &Peer, to: &Peer, request: Request) -> ResponseFuture {
+        let client = self.client.clone();
+        let url = to.to_url(&request.path);
+
+        Box::pin(async move {
+            let mut builder = match (request.method, request.body) {
+                (Method::Get, _) => client.get(&url),
+                (Method::Post, body) => {
+                    let body = body.unwrap_or_default();
+                    client
+                        .post(&url)
+                        .header("Content-Type", "application/json")
+                        .header("Content-Length", body.len())
+                        .body(body)
+                }
+            };
+
+            if let Some(timeout) = request.timeout {
+                builder = builder.timeout(timeout);
+            }
+
+            let resp = builder.send().await.map_err(|e| {
+                if e.is_timeout() {
+                    TransportError::Timeout
+                } else {
+                    TransportError::Unreachable
+                }
+            })?;
+
+            let status = resp.status().as_u16();
+            let body = resp
+                .text()
+                .await
+                .map_err(|e| TransportError::Other(e.to_string()))?;
+
+            Ok(Response { status, body })
+        })
+    }
+}
@@ -1,10 +1,12 @@
-use lazy_static::lazy_static;
+use crate::events::{Event, Events};
+use crate::sync::lock;
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use rand::thread_rng;
 use std::collections::HashMap;
-use std::sync::{Mutex, OnceLock};
+use std::sync::Mutex;
+use tracing::{debug, info};
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct Peer {
     pub ip: String,
     pub port: u16,
@@ -24,67 +26,340 @@ impl Peer {
     }
 }
 
-lazy_static! {
-    static ref KNOWN_PEERS: Mutex<HashMap<Peer, u8>> = Mutex::new(HashMap::new());
+/// Limits for the neighbour set every node maintains.
+///
+/// Outbound neighbours are chosen by us from the known peers, inbound ones
+/// are peers that advertised themselves to us. Gossip and sync only talk to
+/// `fanout` randomly chosen neighbours instead of the whole network.
+#[derive(Debug, Clone)]
+pub struct NeighbourConfig {
+    pub outbound_slots: usize,
+    pub inbound_slots: usize,
+    pub fanout: usize,
 }
 
-pub static SELF_PEER: OnceLock<Peer> = OnceLock::new();
+impl Default for NeighbourConfig {
+    fn default() -> Self {
+        NeighbourConfig {
+            outbound_slots: 8,
+            inbound_slots: 16,
+            fanout: 8,
+        }
+    }
+}
 
-pub fn self_peer() -> &'static Peer {
-    SELF_PEER.get().expect("SELF_PEER not initialized")
+#[derive(Debug, Default)]
+struct Neighbours {
+    config: NeighbourConfig,
+    outbound: Vec<Peer>,
+    inbound: Vec<Peer>,
 }
 
-pub fn set_self_peer(ip: String, port: u16) {
-    add_peer(ip.clone(), port);
+impl Neighbours {
+    fn contains(&self, peer: &Peer) -> bool {
+        self.outbound.contains(peer) || self.inbound.contains(peer)
+    }
+
+    fn remove(&mut self, peer: &Peer) {
+        self.outbound.retain(|p| p != peer);
+        self.inbound.retain(|p| p != peer);
+    }
+}
 
-    let peer = Peer::new(ip, port);
-    SELF_PEER
-        .set(peer)
-        .expect("[ERROR] SELF_PEER value was already set");
+/// Liveness information gathered by the ping loop.
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub score: u8,
+    pub rtt_ms: Option<u64>,
+    pub last_seen: Option<u64>,
+    pub failure_streak: u32,
 }
 
-pub fn add_bootstrap_peers(peers: Vec<(String, u16)>) {
-    let mut known = KNOWN_PEERS.lock().unwrap();
-    for (ip, port) in peers {
-        known.insert(Peer::new(ip, port), std::u8::MAX);
+impl Default for PeerStats {
+    fn default() -> Self {
+        PeerStats {
+            score: u8::MAX,
+            rtt_ms: None,
+            last_seen: None,
+            failure_streak: 0,
+        }
     }
-    println!("[PEERS] Added {} bootstrap peers", known.len());
 }
 
-pub fn add_peer(ip: String, port: u16) -> bool {
-    let peer = Peer::new(ip, port);
-    let mut known = KNOWN_PEERS.lock().unwrap();
+/// Consecutive failed requests after which a neighbour loses its slot.
+pub const MAX_FAILURE_STREAK: u32 = 3;
+
+/// Known peers and the neighbour set of a single node.
+#[derive(Debug)]
+pub struct PeerTable {
+    self_peer: Peer,
+    known: Mutex<HashMap<Peer, PeerStats>>,
+    neighbours: Mutex<Neighbours>,
+    rng: Mutex<StdRng>,
+    events: Events,
+}
+
+impl PeerTable {
+    /// Creates a table that knows only about the node itself. All random
+    /// peer choices are drawn from `rng`, so a seeded generator makes them
+    /// reproducible.
+    pub fn new(self_peer: Peer, config: NeighbourConfig, rng: StdRng) -> Self {
+        let table = PeerTable {
+            self_peer: self_peer.clone(),
+            known: Mutex::new(HashMap::new()),
+            rng: Mutex::new(rng),
+            neighbours: Mutex::new(Neighbours {
+                config,
+                ..Neighbours::default()
+            }),
+            events: Events::new(),
+        };
+
+        table.add_peer(self_peer.ip, self_peer.port);
+        table
+    }
+
+    /// Publishes peers being added and removed to `events`.
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    pub fn self_peer(&self) -> &Peer {
+        &self.self_peer
+    }
+
+    pub fn add_bootstrap_peers(&self, peers: Vec<(String, u16)>) {
+        let mut known = lock(&self.known);
+        for (ip, port) in peers {
+            known.entry(Peer::new(ip, port)).or_default();
+        }
+        info!(count = known.len(), "added bootstrap peers");
+    }
+
+    pub fn add_peer(&self, ip: String, port: u16) -> bool {
+        let peer = Peer::new(ip, port);
+        let mut known = lock(&self.known);
 
-    if known.insert(peer.clone(), std::u8::MAX).is_none() {
-        println!("[PEERS] Added new peer: {}:{}", peer.ip, peer.port);
+        if known.contains_key(&peer) {
+            return false;
+        }
+
+        info!(peer = %peer.socket_addr(), "added new peer");
+        known.insert(peer.clone(), PeerStats::default());
+        self.events.publish(Event::PeerAdded {
+            ip: peer.ip,
+            port: peer.port,
+        });
         true
-    } else {
-        false
     }
-}
 
-pub fn update_peer(p: Peer) {
-    let mut known = KNOWN_PEERS.lock().unwrap();
+    /// Records a failed request to `p`. Peers whose score drops to zero are
+    /// forgotten, neighbours with a long failure streak lose their slot.
+    pub fn update_peer(&self, p: Peer) {
+        let mut known = lock(&self.known);
 
-    if let Some(score) = known.get_mut(&p) {
-        *score = score.saturating_sub(1);
+        if let Some(stats) = known.get_mut(&p) {
+            stats.score = stats.score.saturating_sub(1);
+            stats.failure_streak += 1;
 
-        if *score == 0 {
-            known.remove(&p);
+            if stats.score == 0 {
+                known.remove(&p);
+                lock(&self.neighbours).remove(&p);
+                info!(peer = %p.socket_addr(), "removed unresponsive peer");
+                self.events.publish(Event::PeerRemoved {
+                    ip: p.ip.clone(),
+                    port: p.port,
+                });
+            } else if stats.failure_streak >= MAX_FAILURE_STREAK {
+                lock(&self.neighbours).remove(&p);
+            }
+        }
+    }
+
+    /// Records a successful round trip to `p`.
+    pub fn record_pong(&self, p: &Peer, rtt_ms: u64, now: u64) {
+        let mut known = lock(&self.known);
+
+        if let Some(stats) = known.get_mut(p) {
+            stats.score = u8::MAX;
+            stats.rtt_ms = Some(rtt_ms);
+            stats.last_seen = Some(now);
+            stats.failure_streak = 0;
         }
     }
-}
 
-pub fn get_known_peers() -> Vec<Peer> {
-    let known = KNOWN_PEERS.lock().unwrap();
-    known.iter().map(|(k, _)| k).cloned().collect()
+    /// Known peers with their stats, sorted by address.
+    pub fn get_peer_details(&self) -> Vec<(Peer, PeerStats)> {
+        let known = lock(&self.known);
+        let mut details: Vec<_> = known.iter().map(|(p, s)| (p.clone(), s.clone())).collect();
+        details.sort_by(|a, b| a.0.cmp(&b.0));
+        details
+    }
+
+    /// Known peers sorted by address.
+    pub fn get_known_peers(&self) -> Vec<Peer> {
+        let known = lock(&self.known);
+        let mut peers: Vec<Peer> = known.keys().cloned().collect();
+        peers.sort();
+        peers
+    }
+
+    /// Random sample of known peers, handed out to others during discovery.
+    pub fn select_random_peers(&self) -> Vec<Peer> {
+        let mut peers = self.get_known_peers();
+        peers.shuffle(&mut *lock(&self.rng));
+        peers.into_iter().take(100).collect()
+    }
+
+    /// Up to `fanout` random neighbours to gossip with or sync from.
+    ///
+    /// Falls back to random known peers while the neighbour set is still
+    /// empty, e.g. right after start-up or when every neighbour has been
+    /// dropped.
+    pub fn select_gossip_peers(&self) -> Vec<Peer> {
+        let neighbours = lock(&self.neighbours);
+        let fanout = neighbours.config.fanout;
+
+        let mut peers: Vec<Peer> = neighbours
+            .outbound
+            .iter()
+            .chain(neighbours.inbound.iter())
+            .cloned()
+            .collect();
+        drop(neighbours);
+
+        if peers.is_empty() {
+            peers = self.get_known_peers();
+        }
+
+        peers.shuffle(&mut *lock(&self.rng));
+        peers.into_iter().take(fanout).collect()
+    }
+
+    pub fn outbound_neighbours(&self) -> Vec<Peer> {
+        lock(&self.neighbours).outbound.clone()
+    }
+
+    pub fn inbound_neighbours(&self) -> Vec<Peer> {
+        lock(&self.neighbours).inbound.clone()
+    }
+
+    /// Takes a peer that contacted us into a free inbound slot.
+    pub fn accept_inbound(&self, peer: Peer) -> bool {
+        if peer == self.self_peer {
+            return false;
+        }
+
+        let mut neighbours = lock(&self.neighbours);
+        if neighbours.contains(&peer) {
+            return true;
+        }
+
+        if neighbours.inbound.len() >= neighbours.config.inbound_slots {
+            return false;
+        }
+
+        debug!(peer = %peer.socket_addr(), "accepted inbound neighbour");
+        neighbours.inbound.push(peer);
+        true
+    }
+
+    /// Fills free outbound slots with known peers, preferring the ones that
+    /// answered their last pings and have the lowest round trip time.
+    pub fn refill_neighbours(&self) {
+        self.fill_outbound(None);
+    }
+
+    /// `refill_neighbours`, passing over `excluded`.
+    fn fill_outbound(&self, excluded: Option<&Peer>) {
+        let mut candidates = self.get_peer_details();
+        candidates.shuffle(&mut *lock(&self.rng));
+        candidates.sort_by_key(|(_, s)| (s.failure_streak, s.rtt_ms.unwrap_or(u64::MAX)));
+
+        let mut neighbours = lock(&self.neighbours);
+
+        for (peer, stats) in candidates {
+            if neighbours.outbound.len() >= neighbours.config.outbound_slots {
+                break;
+            }
+
+            if peer == self.self_peer
+                || Some(&peer) == excluded
+                || neighbours.contains(&peer)
+                || stats.failure_streak >= MAX_FAILURE_STREAK
+            {
+                continue;
+            }
+
+            debug!(peer = %peer.socket_addr(), "selected outbound neighbour");
+            neighbours.outbound.push(peer);
+        }
+    }
+
+    /// Replaces one random outbound neighbour and evicts one inbound
+    /// neighbour when the inbound slots are full, so the overlay keeps
+    /// mixing over time. The evicted outbound neighbour sits out the refill,
+    /// or its measured round trip time would win it straight back.
+    pub fn rotate_neighbours(&self) {
+        let evicted = {
+            let mut neighbours = lock(&self.neighbours);
+            let mut rng = lock(&self.rng);
+            let mut evicted = None;
+
+            if neighbours.outbound.len() >= neighbours.config.outbound_slots {
+                evicted = neighbours.outbound.choose(&mut *rng).cloned();
+                neighbours.outbound.retain(|p| Some(p) != evicted.as_ref());
+            }
+
+            if neighbours.inbound.len() >= neighbours.config.inbound_slots {
+                let evicted = neighbours.inbound.choose(&mut *rng).cloned();
+                neighbours.inbound.retain(|p| Some(p) != evicted.as_ref());
+            }
+
+            evicted
+        };
+
+        self.fill_outbound(evicted.as_ref());
+    }
 }
 
-pub fn select_random_peers() -> Vec<Peer> {
-    let peers = KNOWN_PEERS.lock().unwrap();
-    let mut rng = thread_rng();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
 
-    let mut peers: Vec<Peer> = peers.iter().map(|(p, _)| p.clone()).collect();
-    peers.shuffle(&mut rng);
-    peers.into_iter().take(100).collect()
+    #[test]
+    fn rotation_replaces_a_pinged_neighbour() {
+        let config = NeighbourConfig {
+            outbound_slots: 3,
+            ..NeighbourConfig::default()
+        };
+        let table = PeerTable::new(
+            Peer::new("10.0.0.1".into(), 1),
+            config,
+            StdRng::seed_from_u64(7),
+        );
+        for port in 2..12 {
+            table.add_peer("10.0.0.1".into(), port);
+        }
+        table.refill_neighbours();
+
+        // Only the neighbours have been pinged, so they have the best round
+        // trip times of all known peers.
+        let before = table.outbound_neighbours();
+        for peer in &before {
+            table.record_pong(peer, 5, 0);
+        }
+
+        for _ in 0..5 {
+            let previous = table.outbound_neighbours();
+            table.rotate_neighbours();
+            let current = table.outbound_neighbours();
+
+            assert_eq!(current.len(), 3);
+            let evicted: Vec<_> = previous.iter().filter(|p| !current.contains(p)).collect();
+            assert_eq!(evicted.len(), 1, "{:?} -> {:?}", previous, current);
+        }
+    }
 }
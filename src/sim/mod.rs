@@ -0,0 +1,231 @@
+//! In-process multi-node simulation.
+//!
+//! Spins up any number of nodes inside one tokio runtime, connected by a
+//! [`SimNetwork`] instead of real sockets, so tests can inject latency,
+//! partitions, packet loss and crashes and then check that the nodes agree.
+//!
+//! Run under a paused current-thread runtime, a simulation is fully
+//! determined by its seed: nodes use a [`VirtualClock`] and seeded random
+//! number generators, so the same seed replays the same run.
+
+mod network;
+
+pub use network::{NetworkStats, SimNetwork};
+
+use crate::clock::{Clock, VirtualClock};
+use crate::config::NodeConfig;
+use crate::http::server::{HttpHandler, HttpMethod, HttpRequest, HttpResult};
+use crate::node::Node;
+use crate::node::server::RequestHandler;
+use crate::node::transactions;
+use crate::peers::Peer;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{Instant, sleep};
+
+/// Wall clock time the simulated network starts at (2026-01-01T00:00:00Z).
+pub const SIM_EPOCH_MILLIS: u64 = 1_767_225_600_000;
+
+/// Node settings suited for simulation: trivial proof of work and short
+/// loop intervals.
+pub fn sim_config() -> NodeConfig {
+    NodeConfig {
+        difficulty: 1,
+        discovery_interval_secs: 2,
+        block_sync_interval_secs: 2,
+        tx_sync_interval_secs: 2,
+        block_creation_interval_secs: 5,
+        advertisement_interval_secs: 2,
+        ping_interval_secs: 2,
+        neighbour_rotation_interval_secs: 30,
+        ..NodeConfig::default()
+    }
+}
+
+pub struct Simulation {
+    network: Arc<SimNetwork>,
+    clock: Arc<VirtualClock>,
+    seed: u64,
+    restarts: u64,
+    config: NodeConfig,
+    nodes: Vec<Arc<Node>>,
+}
+
+impl Simulation {
+    /// Starts `size` nodes using `config` for everything but the address.
+    /// Node `i` listens on `10.0.0.{i + 1}` and bootstraps from node 0.
+    /// Nodes start their background jobs a little apart, as real nodes would.
+    /// Node `i` draws its random choices from a generator seeded with
+    /// `seed + i`.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub async fn start(size: usize, config: NodeConfig, seed: u64) -> Self {
+        let mut sim = Simulation {
+            network: SimNetwork::new(seed),
+            clock: Arc::new(VirtualClock::new(SIM_EPOCH_MILLIS)),
+            seed,
+            restarts: 0,
+            config,
+            nodes: Vec::with_capacity(size),
+        };
+
+        for i in 0..size {
+            let node = sim.spawn_node(i);
+            sim.nodes.push(node);
+            sleep(Duration::from_millis(250)).await;
+        }
+
+        sim
+    }
+
+    fn spawn_node(&self, i: usize) -> Arc<Node> {
+        let bootstrap = self.peer(0);
+        // Restarted nodes get a fresh seed so they don't replay the choices
+        // of the node they replace.
+        let seed = self.seed + i as u64 + self.restarts * self.nodes.len() as u64;
+        let config = NodeConfig {
+            ip: format!("10.0.0.{}", i + 1),
+            seed: Some(seed),
+            ..self.config.clone()
+        };
+
+        let node = Node::builder(config)
+            .transport(self.network.transport())
+            .clock(Arc::clone(&self.clock) as Arc<dyn Clock>)
+            .bootstrap_peers(vec![(bootstrap.ip, bootstrap.port)])
+            .build();
+
+        self.network.register(&node);
+        node.start_background_jobs();
+        node
+    }
+
+    pub fn network(&self) -> &Arc<SimNetwork> {
+        &self.network
+    }
+
+    pub fn clock(&self) -> &VirtualClock {
+        &self.clock
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node(&self, i: usize) -> &Arc<Node> {
+        &self.nodes[i]
+    }
+
+    pub fn peer(&self, i: usize) -> Peer {
+        Peer::new(format!("10.0.0.{}", i + 1), self.config.port)
+    }
+
+    /// Splits the network into groups of node indices.
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let groups: Vec<Vec<Peer>> = groups
+            .iter()
+            .map(|g| g.iter().map(|&i| self.peer(i)).collect())
+            .collect();
+        self.network.partition(&groups);
+    }
+
+    pub fn heal(&self) {
+        self.network.heal();
+    }
+
+    /// Stops node `i` and cuts it off the network. Its state is kept until
+    /// it is restarted.
+    pub fn crash(&self, i: usize) {
+        self.nodes[i].stop_background_jobs();
+        self.network.crash(&self.peer(i));
+    }
+
+    /// Replaces node `i` with a fresh node at the same address, which has
+    /// to catch up from its peers like a node restarted from scratch.
+    pub fn restart(&mut self, i: usize) {
+        self.nodes[i].stop_background_jobs();
+        self.restarts += 1;
+        self.nodes[i] = self.spawn_node(i);
+    }
+
+    pub fn is_running(&self, i: usize) -> bool {
+        !self.network.is_crashed(&self.peer(i))
+    }
+
+    /// Sends a request straight to node `i`, as an external client would.
+    pub fn request(&self, i: usize, method: HttpMethod, body: &str) -> HttpResult {
+        let request = HttpRequest::new(method, body.into());
+        RequestHandler::new(Arc::clone(&self.nodes[i])).handle(request)
+    }
+
+    pub fn last_block_hash(&self, i: usize) -> String {
+        self.nodes[i].ledger().last_block_hash()
+    }
+
+    pub fn chain_len(&self, i: usize) -> usize {
+        self.nodes[i].ledger().chain_len()
+    }
+
+    /// Main chain block hashes of every node, a compact fingerprint of the
+    /// whole network's state.
+    pub fn chains(&self) -> Vec<Vec<String>> {
+        self.nodes
+            .iter()
+            .map(|n| n.ledger().get_all_block_hashes())
+            .collect()
+    }
+
+    pub fn balances(&self, i: usize) -> HashMap<String, i64> {
+        self.nodes[i]
+            .ledger()
+            .with_blocks(transactions::compute_balances)
+    }
+
+    /// Whether every running node has the same chain tip.
+    pub fn converged(&self) -> bool {
+        let mut tips = (0..self.len())
+            .filter(|&i| self.is_running(i))
+            .map(|i| self.last_block_hash(i));
+
+        match tips.next() {
+            Some(first) => tips.all(|tip| tip == first),
+            None => true,
+        }
+    }
+
+    /// Waits until `condition` holds, checking every 100ms of (possibly
+    /// simulated) time. Returns `false` on timeout.
+    pub async fn wait_until(&self, timeout: Duration, condition: impl Fn(&Self) -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline {
+            if condition(self) {
+                return true;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        condition(self)
+    }
+
+    pub async fn wait_for_convergence(&self, timeout: Duration) -> bool {
+        self.wait_until(timeout, Simulation::converged).await
+    }
+
+    pub fn shutdown(&self) {
+        for node in &self.nodes {
+            node.stop_background_jobs();
+        }
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
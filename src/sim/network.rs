@@ -0,0 +1,202 @@
+use crate::http::server::{HttpHandler, HttpMethod, HttpRequest, HttpResult};
+use crate::node::Node;
+use crate::node::server::RequestHandler;
+use crate::node::transport::{
+    Method, Request, Response, ResponseFuture, Transport, TransportError,
+};
+use crate::peers::Peer;
+use crate::sync::lock;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// How long a sender waits for a dropped request that has no timeout of
+/// its own before giving up.
+const DEFAULT_DROP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NetworkStats {
+    pub delivered: u64,
+    pub dropped: u64,
+}
+
+struct NetworkState {
+    nodes: HashMap<Peer, Weak<Node>>,
+    latency: Duration,
+    jitter: Duration,
+    loss: f64,
+    groups: HashMap<Peer, usize>,
+    crashed: HashSet<Peer>,
+    rng: StdRng,
+    stats: NetworkStats,
+}
+
+impl NetworkState {
+    fn reachable(&self, from: &Peer, to: &Peer) -> bool {
+        let group = |p: &Peer| self.groups.get(p).copied().unwrap_or(0);
+
+        !self.crashed.contains(from)
+            && !self.crashed.contains(to)
+            && group(from) == group(to)
+            && self.nodes.get(to).is_some_and(|n| n.strong_count() > 0)
+    }
+
+    fn delay(&mut self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.latency;
+        }
+
+        let jitter = self.rng.gen_range(0..=self.jitter.as_micros() as u64);
+        self.latency + Duration::from_micros(jitter)
+    }
+}
+
+/// In-process network connecting simulated nodes.
+///
+/// Requests are handed straight to the target node's `RequestHandler`
+/// after a configurable one-way latency. Links can be cut by partitioning
+/// the nodes into groups, by crashing nodes, or randomly via packet loss.
+pub struct SimNetwork {
+    state: Mutex<NetworkState>,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64) -> Arc<Self> {
+        Arc::new(SimNetwork {
+            state: Mutex::new(NetworkState {
+                nodes: HashMap::new(),
+                latency: Duration::from_millis(10),
+                jitter: Duration::ZERO,
+                loss: 0.0,
+                groups: HashMap::new(),
+                crashed: HashSet::new(),
+                rng: StdRng::seed_from_u64(seed),
+                stats: NetworkStats::default(),
+            }),
+        })
+    }
+
+    /// Transport handle to give to a node built for this network.
+    pub fn transport(self: &Arc<Self>) -> Arc<dyn Transport> {
+        Arc::new(SimTransport {
+            network: Arc::clone(self),
+        })
+    }
+
+    pub fn register(&self, node: &Arc<Node>) {
+        let peer = node.peers().self_peer().clone();
+        let mut state = lock(&self.state);
+        state.crashed.remove(&peer);
+        state.nodes.insert(peer, Arc::downgrade(node));
+    }
+
+    pub fn set_latency(&self, latency: Duration, jitter: Duration) {
+        let mut state = lock(&self.state);
+        state.latency = latency;
+        state.jitter = jitter;
+    }
+
+    /// Probability in `[0, 1]` that a request is lost on the way.
+    pub fn set_packet_loss(&self, loss: f64) {
+        lock(&self.state).loss = loss.clamp(0.0, 1.0);
+    }
+
+    /// Splits the network so that only peers in the same group can talk.
+    /// Peers not listed in any group end up together in a group of their own.
+    pub fn partition(&self, groups: &[Vec<Peer>]) {
+        let mut state = lock(&self.state);
+        state.groups.clear();
+
+        for (i, group) in groups.iter().enumerate() {
+            for peer in group {
+                state.groups.insert(peer.clone(), i + 1);
+            }
+        }
+    }
+
+    pub fn heal(&self) {
+        lock(&self.state).groups.clear();
+    }
+
+    /// Makes `peer` unreachable and stops it from reaching anyone else.
+    pub fn crash(&self, peer: &Peer) {
+        lock(&self.state).crashed.insert(peer.clone());
+    }
+
+    pub fn is_crashed(&self, peer: &Peer) -> bool {
+        lock(&self.state).crashed.contains(peer)
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        lock(&self.state).stats
+    }
+
+    fn route(&self, from: &Peer, to: &Peer) -> (Duration, bool) {
+        let mut state = lock(&self.state);
+        let delay = state.delay();
+        let loss = state.loss;
+        let lost = loss > 0.0 && state.rng.gen_bool(loss);
+        (delay, !lost && state.reachable(from, to))
+    }
+
+    fn deliver(&self, from: &Peer, to: &Peer, request: Request) -> Option<HttpResult> {
+        let node = {
+            let mut state = lock(&self.state);
+            if !state.reachable(from, to) {
+                state.stats.dropped += 1;
+                return None;
+            }
+
+            state.stats.delivered += 1;
+            state.nodes.get(to)?.upgrade()?
+        };
+
+        let body = request.body.unwrap_or_default();
+        let method = match request.method {
+            Method::Get => HttpMethod::GET(request.path),
+            Method::Post => HttpMethod::POST(request.path),
+        };
+
+        let mut http_request = HttpRequest::new(method, body);
+        http_request.remote_addr = Some(from.socket_addr());
+
+        let span = node.span().clone();
+        Some(span.in_scope(|| RequestHandler::new(node).handle(http_request)))
+    }
+}
+
+struct SimTransport {
+    network: Arc<SimNetwork>,
+}
+
+impl Transport for SimTransport {
+    fn send(&self, from: &Peer, to: &Peer, request: Request) -> ResponseFuture {
+        let network = Arc::clone(&self.network);
+        let (from, to) = (from.clone(), to.clone());
+
+        Box::pin(async move {
+            let (delay, delivered) = network.route(&from, &to);
+
+            if !delivered {
+                lock(&network.state).stats.dropped += 1;
+                sleep(request.timeout.unwrap_or(DEFAULT_DROP_TIMEOUT)).await;
+                return Err(TransportError::Timeout);
+            }
+
+            sleep(delay).await;
+            let Some(result) = network.deliver(&from, &to, request) else {
+                return Err(TransportError::Unreachable);
+            };
+            let body = result.body.into_string().await;
+            sleep(delay).await;
+
+            Ok(Response {
+                status: result.status,
+                body,
+            })
+        })
+    }
+}
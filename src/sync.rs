@@ -0,0 +1,13 @@
+//! Locking that outlives panics.
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Locks `mutex` even if a thread panicked while holding it.
+///
+/// Handlers run behind `CatchPanic`, so a panic in one request must not lock
+/// every later request and background loop out of the ledger, peer table or
+/// metrics. A panic part-way through an update may leave the data half
+/// changed, which the node copes with far better than with a lock that stays poisoned.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
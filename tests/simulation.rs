@@ -0,0 +1,628 @@
+use p2p::http::middleware::{CatchPanic, HttpHandlerExt};
+use p2p::http::server::{Body, HttpHandler, HttpMethod, HttpRequest};
+use p2p::ledger::{Block, Transaction};
+use p2p::node::protocol::{PageDto, SubmittedDto, TransactionDto, UserDto};
+use p2p::node::server::RequestHandler;
+use p2p::sim::{Simulation, sim_config};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn post(path: &str) -> HttpMethod {
+    HttpMethod::POST(path.into())
+}
+
+fn get(path: &str) -> HttpMethod {
+    HttpMethod::GET(path.into())
+}
+
+#[tokio::test(start_paused = true)]
+async fn nodes_converge_on_submitted_transactions() {
+    let sim = Simulation::start(5, sim_config(), 1).await;
+
+    let res = sim.request(0, post("/users"), r#"{"name":"Bob","balance":50}"#);
+    assert_eq!(res.status, 201);
+    let location = res.headers["Location"].clone();
+    let submitted: SubmittedDto = serde_json::from_str(&res.body.into_string().await).unwrap();
+    assert_eq!(location, format!("/transactions/{}", submitted.hash));
+
+    let mined = sim
+        .wait_until(Duration::from_secs(60), |s| {
+            s.converged() && (0..s.len()).all(|i| s.chain_len(i) == 2)
+        })
+        .await;
+    assert!(mined, "nodes did not agree on the block with Bob");
+
+    for i in 0..sim.len() {
+        assert_eq!(sim.balances(i).get("Bob"), Some(&50));
+        assert_eq!(sim.balances(i), sim.balances(0));
+
+        let status = sim.request(i, get(&location), "").body.into_string().await;
+        assert!(status.contains(r#""state":"confirmed""#), "{}", status);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn event_stream_sends_events_and_keepalives_until_shutdown() {
+    let sim = Simulation::start(2, sim_config(), 14).await;
+    assert_eq!(
+        sim.request(1, get("/events?types=block,nonsense"), "")
+            .status,
+        400
+    );
+
+    let res = sim.request(1, get("/events?types=tx_accepted,tx_confirmed"), "");
+    assert_eq!(res.status, 200);
+    assert_eq!(res.content_type, "text/event-stream");
+    assert_eq!(res.headers["Cache-Control"], "no-cache");
+    let Body::Stream(mut stream) = res.body else {
+        panic!("events are not streamed");
+    };
+
+    // The other node sees the transaction arrive, then get mined.
+    let res = sim.request(0, post("/users"), r#"{"name":"Bob","balance":5}"#);
+    let submitted: SubmittedDto = serde_json::from_str(&res.body.into_string().await).unwrap();
+
+    for expected in ["tx_accepted", "tx_confirmed"] {
+        let piece = stream.recv().await.unwrap();
+        let (name, data) = piece
+            .strip_suffix("\n\n")
+            .and_then(|message| message.split_once('\n'))
+            .unwrap_or_else(|| panic!("bad message {:?}", piece));
+        assert_eq!(name, format!("event: {}", expected));
+        let data: serde_json::Value =
+            serde_json::from_str(data.strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(data["hash"], submitted.hash.as_str());
+    }
+
+    // Filtered out events don't count as traffic, so the stream goes quiet
+    // and sends a comment instead.
+    assert_eq!(stream.recv().await.unwrap(), ": keepalive\n\n");
+
+    sim.node(1).begin_shutdown();
+    assert_eq!(stream.recv().await, None);
+}
+
+#[tokio::test(start_paused = true)]
+async fn existing_user_cannot_be_created_again() {
+    let sim = Simulation::start(1, sim_config(), 11).await;
+
+    let res = sim.request(0, post("/users"), r#"{"name":"Bob","balance":50}"#);
+    assert_eq!(res.status, 201);
+    let res = sim.request(0, post("/users"), r#"{"name":"Bob","balance":5}"#);
+    assert_eq!(res.status, 409);
+
+    // Still refused once the first one is mined.
+    let mined = sim
+        .wait_until(Duration::from_secs(60), |s| s.chain_len(0) == 2)
+        .await;
+    assert!(mined);
+    let res = sim.request(0, post("/users"), r#"{"name":"Bob","balance":5}"#);
+    assert_eq!(res.status, 409);
+    assert_eq!(sim.balances(0).get("Bob"), Some(&50));
+}
+
+#[tokio::test(start_paused = true)]
+async fn transfer_sent_twice_is_accepted_once() {
+    let sim = Simulation::start(1, sim_config(), 12).await;
+
+    // Sent twice within a second, the transfer is only accepted once.
+    let transfer = r#"{"from":"Alice","to":"Carol","sum":1}"#;
+    let first = sim.request(0, post("/transfers"), transfer);
+    let second = sim.request(0, post("/transfers"), transfer);
+    assert_eq!((first.status, second.status), (201, 200));
+    let first: SubmittedDto = serde_json::from_str(&first.body.into_string().await).unwrap();
+    let second: SubmittedDto = serde_json::from_str(&second.body.into_string().await).unwrap();
+    assert_eq!(first.hash, second.hash);
+    assert_eq!(sim.node(0).ledger().pending_txs_len(), 1);
+
+    let mined = sim
+        .wait_until(Duration::from_secs(60), |s| s.chain_len(0) == 2)
+        .await;
+    assert!(mined);
+    assert_eq!(sim.balances(0).get("Carol"), Some(&1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn malformed_transactions_report_where_they_are() {
+    let sim = Simulation::start(1, sim_config(), 15).await;
+    let status = |hash: &str| {
+        let res = sim.request(0, get(&format!("/transactions/{}", hash)), "");
+        async move { serde_json::from_str::<serde_json::Value>(&res.body.into_string().await).unwrap() }
+    };
+
+    let pending = Transaction::new("Bob gets everything".into(), 1);
+    let body = format!(
+        r#"{{"hash":"{}","data":"{}","timestamp":1}}"#,
+        pending.hash, pending.data
+    );
+    assert_eq!(sim.request(0, post("/transactions"), &body).status, 201);
+    let dto = status(&pending.hash).await;
+    assert_eq!(dto["state"], "malformed");
+    assert_eq!(dto["block_hash"], serde_json::Value::Null);
+    assert_eq!(dto["confirmations"], 0);
+
+    let mined = Transaction::new("not a transfer".into(), 2);
+    let block = Block::new(
+        sim.last_block_hash(0),
+        vec![mined.clone()],
+        2,
+        sim_config().difficulty,
+    );
+    sim.node(0).ledger().add_block(&block);
+    let dto = status(&mined.hash).await;
+    assert_eq!(dto["state"], "malformed");
+    assert_eq!(dto["block_hash"], block.hash.as_str());
+    assert_eq!(dto["block_height"], 2);
+    assert_eq!(dto["confirmations"], 1);
+
+    assert_eq!(status("f00d").await["state"], "unknown");
+}
+
+#[tokio::test(start_paused = true)]
+async fn list_pages_cover_every_item_once() {
+    let sim = Simulation::start(1, sim_config(), 13).await;
+    for i in 0..105 {
+        let user = format!(r#"{{"name":"U{:03}","balance":1}}"#, i);
+        assert_eq!(sim.request(0, post("/users"), &user).status, 201);
+    }
+
+    // A limit above the maximum is cut down to it.
+    let pages: Vec<Vec<TransactionDto>> = walk_pages(&sim, "/v2/transactions?limit=1000").await;
+    let sizes: Vec<usize> = pages.iter().map(Vec::len).collect();
+    assert_eq!(sizes, [100, 5]);
+
+    let pages: Vec<Vec<TransactionDto>> = walk_pages(&sim, "/v2/transactions?limit=7").await;
+    assert!(pages.iter().all(|page| page.len() <= 7));
+    let hashes: Vec<String> = pages.into_iter().flatten().map(|tx| tx.hash).collect();
+    let pending: Vec<String> = sim
+        .node(0)
+        .ledger()
+        .get_pending_transactions()
+        .into_iter()
+        .map(|tx| tx.hash)
+        .collect();
+    assert_eq!(hashes.len(), 105);
+    assert_eq!(hashes, pending);
+
+    let mined = sim
+        .wait_until(Duration::from_secs(60), |s| {
+            s.node(0).ledger().pending_txs_len() == 0
+        })
+        .await;
+    assert!(mined);
+
+    // Paging one user at a time still lists every user once.
+    let pages: Vec<Vec<UserDto>> = walk_pages(&sim, "/v2/users?limit=1").await;
+    assert!(pages.iter().all(|page| page.len() == 1));
+    let names: Vec<String> = pages.into_iter().flatten().map(|user| user.name).collect();
+    let mut expected: Vec<String> = sim.balances(0).into_keys().collect();
+    expected.sort();
+    assert_eq!(names.len(), 106);
+    assert_eq!(names, expected);
+
+    let pages: Vec<Vec<String>> = walk_pages(&sim, "/v2/hashes?limit=1").await;
+    let hashes: Vec<String> = pages.into_iter().flatten().collect();
+    assert_eq!(hashes, sim.node(0).ledger().get_all_block_hashes());
+    let after_genesis = format!("/v2/hashes/{}?limit=1", hashes[0]);
+    let pages: Vec<Vec<String>> = walk_pages(&sim, &after_genesis).await;
+    assert_eq!(pages.concat(), hashes[1..]);
+
+    for path in [
+        "/v2/transactions?cursor=zz",
+        "/v2/users?cursor=not-hex",
+        "/v2/hashes?cursor=6869",
+        "/v2/transfers?cursor=1",
+        "/v2/users?limit=0",
+        "/v2/hashes?limit=many",
+    ] {
+        assert_eq!(sim.request(0, get(path), "").status, 400, "{}", path);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn account_history_pages_stay_put_when_blocks_are_mined() {
+    let sim = Simulation::start(3, sim_config(), 5).await;
+
+    for sum in 1..=3 {
+        let body = format!(r#"{{"from":"Alice","to":"Bob","sum":{}}}"#, sum);
+        assert_eq!(sim.request(0, post("/transfers"), &body).status, 201);
+    }
+    let mined = sim
+        .wait_until(Duration::from_secs(60), |s| {
+            s.converged() && s.balances(0).get("Bob") == Some(&6)
+        })
+        .await;
+    assert!(mined);
+
+    let history = |path: String| {
+        let body = sim.request(0, get(&path), "").body;
+        async move {
+            let page: serde_json::Value = serde_json::from_str(&body.into_string().await).unwrap();
+            let sums: Vec<i64> = page["items"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["sum"].as_i64().unwrap())
+                .collect();
+            (sums, page["next_cursor"].as_str().map(str::to_string))
+        }
+    };
+
+    let (first, cursor) = history("/users/Alice/history?limit=2".into()).await;
+    assert_eq!(first.len(), 2);
+    let cursor = cursor.expect("a second page");
+
+    // A newer transfer lands on top of the history between the two pages.
+    sim.request(
+        0,
+        post("/transfers"),
+        r#"{"from":"Alice","to":"Bob","sum":4}"#,
+    );
+    let mined = sim
+        .wait_until(Duration::from_secs(60), |s| {
+            s.converged() && s.balances(0).get("Bob") == Some(&10)
+        })
+        .await;
+    assert!(mined);
+
+    let (second, next) = history(format!("/users/Alice/history?limit=2&cursor={}", cursor)).await;
+    assert_eq!(next, None);
+
+    let mut seen: Vec<i64> = first.into_iter().chain(second).collect();
+    seen.sort();
+    assert_eq!(seen, vec![1, 2, 3]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn users_are_listed_at_past_and_off_chain_blocks() {
+    let sim = Simulation::start(1, sim_config(), 6).await;
+    let genesis = sim.last_block_hash(0);
+
+    sim.request(0, post("/users"), r#"{"name":"Bob","balance":7}"#);
+    let mined = sim
+        .wait_until(Duration::from_secs(60), |s| s.chain_len(0) == 2)
+        .await;
+    assert!(mined);
+
+    // A block competing with the tip, which stays off the main chain.
+    let tx = Transaction::new("Alice->Carol:3".into(), 1);
+    let fork = Block::new(genesis, vec![tx], 1, sim_config().difficulty);
+    sim.node(0).ledger().add_block(&fork);
+    assert_eq!(sim.chain_len(0), 2);
+
+    let users = |query: String| {
+        let res = sim.request(0, get(&format!("/v2/users?{}", query)), "");
+        async move {
+            let body = res.body.into_string().await;
+            (
+                res.status,
+                serde_json::from_str::<serde_json::Value>(&body).ok(),
+            )
+        }
+    };
+
+    let (status, page) = users(format!("at_block={}", fork.hash)).await;
+    let page = page.unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(page["at"]["height"], 2);
+    assert_eq!(page["at"]["main_chain"], false);
+    let names: Vec<&str> = page["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| user["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["Alice", "Carol"]);
+
+    let (status, page) = users("at_height=2".into()).await;
+    let page = page.unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(page["at"]["main_chain"], true);
+    assert_eq!(page["items"][1]["name"], "Bob");
+
+    assert_eq!(
+        users("at_height=1".into()).await.1.unwrap()["items"][1],
+        serde_json::Value::Null
+    );
+    assert_eq!(users("at_height=0".into()).await.0, 404);
+    assert_eq!(users("at_height=3".into()).await.0, 404);
+    assert_eq!(users("at_height=abc".into()).await.0, 400);
+    assert_eq!(users("at_block=unknown".into()).await.0, 404);
+    assert_eq!(
+        users(format!("at_height=2&at_block={}", fork.hash)).await.0,
+        400
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn blocks_are_served_by_height_range_and_fork() {
+    let sim = Simulation::start(1, sim_config(), 10).await;
+    let difficulty = sim_config().difficulty;
+
+    // Main chain up to height 6, and a fork off the block at height 3.
+    let mut main = vec![sim.last_block_hash(0)];
+    for ts in 2..=6 {
+        let block = Block::new(main.last().unwrap().clone(), vec![], ts, difficulty);
+        sim.node(0).ledger().add_block(&block);
+        main.push(block.hash);
+    }
+    let fork = Block::new(main[2].clone(), vec![], 100, difficulty);
+    sim.node(0).ledger().add_block(&fork);
+    assert_eq!(sim.chain_len(0), 6);
+
+    let json = |path: String| {
+        let res = sim.request(0, get(&path), "");
+        async move {
+            let body = res.body.into_string().await;
+            (
+                res.status,
+                serde_json::from_str::<serde_json::Value>(&body).unwrap_or_default(),
+            )
+        }
+    };
+    let heights = |page: &serde_json::Value| -> Vec<u64> {
+        page["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|block| block["height"].as_u64().unwrap())
+            .collect()
+    };
+
+    let (status, page) = json("/blocks?start=2&end=4".into()).await;
+    assert_eq!(status, 200);
+    assert_eq!(heights(&page), [2, 3, 4]);
+    assert_eq!(page["items"][0]["hash"], main[1].as_str());
+    assert_eq!(page["next_cursor"], serde_json::Value::Null);
+
+    let (_, page) = json("/blocks?start=2&end=5&limit=2".into()).await;
+    assert_eq!(heights(&page), [2, 3]);
+    let cursor = page["next_cursor"].as_str().unwrap().to_string();
+    let (_, page) = json(format!("/blocks?start=2&end=5&limit=2&cursor={}", cursor)).await;
+    assert_eq!(heights(&page), [4, 5]);
+    assert_eq!(page["next_cursor"], serde_json::Value::Null);
+
+    let (_, page) = json("/blocks?start=5".into()).await;
+    assert_eq!(heights(&page), [5, 6]);
+    let (_, page) = json("/blocks?start=7".into()).await;
+    assert_eq!(heights(&page), Vec::<u64>::new());
+    assert_eq!(json("/blocks?start=0".into()).await.0, 400);
+    assert_eq!(json("/blocks?start=4&end=3".into()).await.0, 400);
+
+    let (status, block) = json("/blocks/height/6".into()).await;
+    assert_eq!(status, 200);
+    assert_eq!(block["hash"], main[5].as_str());
+    assert_eq!(json("/blocks/height/0".into()).await.0, 404);
+    assert_eq!(json("/blocks/height/7".into()).await.0, 404);
+    assert_eq!(json("/blocks/height/top".into()).await.0, 400);
+
+    let (status, children) = json(format!("/blocks/{}/children", main[2])).await;
+    assert_eq!(status, 200);
+    let mut children: Vec<(String, u64, bool)> = children
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|child| {
+            (
+                child["hash"].as_str().unwrap().to_string(),
+                child["height"].as_u64().unwrap(),
+                child["main_chain"].as_bool().unwrap(),
+            )
+        })
+        .collect();
+    children.sort_by_key(|child| !child.2);
+    assert_eq!(
+        children,
+        [(main[3].clone(), 4, true), (fork.hash.clone(), 4, false)]
+    );
+    let (_, children) = json(format!("/blocks/{}/children", main[5])).await;
+    assert_eq!(children, serde_json::json!([]));
+    assert_eq!(json("/blocks/unknown/children".into()).await.0, 404);
+
+    let (status, tips) = json("/chain/tips".into()).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        tips,
+        serde_json::json!([
+            {"hash": main[5], "height": 6, "main_chain": true},
+            {"hash": fork.hash, "height": 4, "main_chain": false},
+        ])
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn partitioned_network_converges_after_healing() {
+    let sim = Simulation::start(6, sim_config(), 2).await;
+    assert!(sim.wait_for_convergence(Duration::from_secs(10)).await);
+
+    sim.partition(&[&[0, 1, 2], &[3, 4, 5]]);
+    sim.request(0, post("/users"), r#"{"name":"Bob","balance":10}"#);
+    sim.request(3, post("/users"), r#"{"name":"Carol","balance":20}"#);
+
+    let forked = sim
+        .wait_until(Duration::from_secs(30), |s| {
+            s.last_block_hash(0) != s.last_block_hash(3)
+                && (0..s.len()).all(|i| s.chain_len(i) == 2)
+        })
+        .await;
+    assert!(
+        forked,
+        "each side of the partition should mine its own block"
+    );
+
+    sim.heal();
+    sim.request(1, post("/users"), r#"{"name":"Dave","balance":30}"#);
+
+    assert!(sim.wait_for_convergence(Duration::from_secs(120)).await);
+    let balances = sim.balances(0);
+    assert_eq!(balances.get("Dave"), Some(&30));
+    for i in 1..sim.len() {
+        assert_eq!(sim.balances(i), balances);
+    }
+
+    // At least the losing side dropped its own blocks for the other side's.
+    let reorged = (0..sim.len())
+        .filter(|&i| sim.node(i).ledger().reorg_stats().max_depth > 0)
+        .count();
+    assert!(reorged >= 3);
+
+    // Their abandoned branch is still in the block tree as a fork.
+    for i in 0..sim.len() {
+        let tips = sim.node(i).ledger().chain_tips();
+        assert!(tips[0].on_main_chain);
+        assert_eq!(tips[0].hash, sim.last_block_hash(i));
+        if sim.node(i).ledger().reorg_stats().count > 0 {
+            assert!(tips.len() > 1);
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn network_converges_despite_packet_loss_and_latency() {
+    let sim = Simulation::start(5, sim_config(), 3).await;
+    sim.network()
+        .set_latency(Duration::from_millis(50), Duration::from_millis(100));
+    sim.network().set_packet_loss(0.2);
+
+    sim.request(2, post("/users"), r#"{"name":"Bob","balance":5}"#);
+
+    let mined = sim
+        .wait_until(Duration::from_secs(120), |s| {
+            s.converged() && s.balances(0).get("Bob") == Some(&5)
+        })
+        .await;
+    assert!(mined);
+    assert!(sim.network().stats().dropped > 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn crashed_node_catches_up_after_restart() {
+    let mut sim = Simulation::start(4, sim_config(), 4).await;
+
+    sim.crash(3);
+    sim.request(0, post("/users"), r#"{"name":"Bob","balance":7}"#);
+
+    sim.request(
+        0,
+        post("/transfers"),
+        r#"{"from":"Alice","to":"Carol","sum":1}"#,
+    );
+
+    let mined = sim
+        .wait_until(Duration::from_secs(60), |s| {
+            s.converged() && s.chain_len(0) == 2
+        })
+        .await;
+    assert!(mined);
+    assert_eq!(sim.chain_len(3), 1);
+
+    sim.restart(3);
+    assert!(sim.wait_for_convergence(Duration::from_secs(60)).await);
+    assert_eq!(sim.balances(3).get("Bob"), Some(&7));
+    assert_eq!(sim.balances(3).get("Carol"), Some(&1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn metrics_endpoint_serves_parseable_samples() {
+    let sim = Simulation::start(3, sim_config(), 9).await;
+    sim.request(0, post("/users"), r#"{"name":"Bob","balance":5}"#);
+    let mined = sim
+        .wait_until(Duration::from_secs(60), |s| {
+            s.converged() && s.chain_len(0) == 2
+        })
+        .await;
+    assert!(mined);
+
+    let res = sim.request(1, get("/metrics"), "");
+    assert_eq!(res.status, 200);
+    assert!(res.content_type.starts_with("text/plain"));
+    let body = res.body.into_string().await;
+
+    let mut samples = HashMap::new();
+    for line in body.lines().filter(|l| !l.starts_with('#')) {
+        let (series, value) = line.rsplit_once(' ').unwrap();
+        let value: f64 = value
+            .parse()
+            .unwrap_or_else(|_| panic!("bad sample {}", line));
+        samples.insert(series.to_string(), value);
+    }
+
+    assert_eq!(samples["p2p_chain_height"], 2.0);
+    assert_eq!(samples["p2p_sync_lag_blocks"], 0.0);
+    assert_eq!(samples[r#"p2p_peers{state="known"}"#], 3.0);
+    let sent: f64 = samples
+        .iter()
+        .filter(|(series, _)| series.starts_with("p2p_messages_sent_total{"))
+        .map(|(_, value)| value)
+        .sum();
+    assert!(sent > 0.0, "{}", body);
+}
+
+#[tokio::test(start_paused = true)]
+async fn panic_while_holding_the_ledger_lock_fails_only_its_request() {
+    let sim = Simulation::start(1, sim_config(), 8).await;
+    let node = Arc::clone(sim.node(0));
+    let handler = RequestHandler::new(Arc::clone(&node))
+        .layer(move |req: HttpRequest, next: &dyn HttpHandler| {
+            if req.method.path() == "/boom" {
+                node.ledger().with_blocks(|_| panic!("boom"));
+            }
+            next.handle(req)
+        })
+        .layer(CatchPanic);
+
+    let boom = HttpRequest::new(get("/boom"), String::new());
+    assert_eq!(handler.handle(boom).status, 500);
+
+    let user = HttpRequest::new(post("/users"), r#"{"name":"Bob","balance":3}"#.into());
+    assert_eq!(handler.handle(user).status, 201);
+    let users = HttpRequest::new(get("/users"), String::new());
+    let body = handler.handle(users).body.into_string().await;
+    assert!(body.contains(r#""name":"Alice""#), "{}", body);
+}
+
+async fn run_lossy_scenario(seed: u64) -> (Vec<Vec<String>>, u64) {
+    let sim = Simulation::start(6, sim_config(), seed).await;
+    sim.network()
+        .set_latency(Duration::from_millis(20), Duration::from_millis(200));
+    sim.network().set_packet_loss(0.3);
+
+    for (i, name) in ["Bob", "Carol", "Dave"].iter().enumerate() {
+        let body = format!(r#"{{"name":"{}","balance":{}}}"#, name, i + 1);
+        sim.request(i * 2, post("/users"), &body);
+        tokio::time::sleep(Duration::from_millis(1700)).await;
+    }
+
+    tokio::time::sleep(Duration::from_secs(60)).await;
+    (sim.chains(), sim.network().stats().dropped)
+}
+
+#[tokio::test(start_paused = true)]
+async fn same_seed_replays_identically() {
+    let first = run_lossy_scenario(42).await;
+    let second = run_lossy_scenario(42).await;
+
+    assert!(first.0.iter().all(|chain| chain.len() > 1));
+    assert_eq!(first, second);
+}
+
+/// Follows `next_cursor` from `path`, which already has a query string, to
+/// the last page and returns the items of every page.
+async fn walk_pages<T: DeserializeOwned>(sim: &Simulation, path: &str) -> Vec<Vec<T>> {
+    let mut pages = Vec::new();
+    let mut next = path.to_string();
+
+    loop {
+        let res = sim.request(0, get(&next), "");
+        assert_eq!(res.status, 200, "{}", next);
+        let page: PageDto<T> = serde_json::from_str(&res.body.into_string().await).unwrap();
+        pages.push(page.items);
+
+        match page.next_cursor {
+            Some(cursor) => next = format!("{}&cursor={}", path, cursor),
+            None => return pages,
+        }
+    }
+}